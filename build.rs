@@ -19,6 +19,7 @@ fn main() {
     let mcu = avr_mcu::current::mcu().expect("must be building for an AVR target");
 
     genmcu(&outdir, &mcu).expect("failed to generate mcu data");
+    gengpio(&outdir, &mcu).expect("failed to generate gpio data");
 }
 
 fn or_name<'a>(caption: &'a String, name: &'a String) -> &'a String {
@@ -58,6 +59,11 @@ fn genmcu(outdir: &PathBuf, mcu: &avr_mcu::Mcu) -> std::io::Result<()> {
     for interrupt in mcu.device.interrupts.iter() {
         writeln!(mcu_def, "/// irq_handler!({}, my_fn);", interrupt.name)?;
     }
+    for interrupt in mcu.device.interrupts.iter() {
+        // Allow the handwritten code to test whether a given
+        // interrupt vector exists on this MCU
+        println!("cargo:rustc-cfg=AVR_IRQ_{}", interrupt.name);
+    }
     writeln!(mcu_def, "#[macro_export]")?;
     writeln!(mcu_def, "macro_rules! irq_handler {{")?;
     for interrupt in mcu.device.interrupts.iter() {
@@ -342,3 +348,125 @@ fn genmcu(outdir: &PathBuf, mcu: &avr_mcu::Mcu) -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// Emit a Port implementation and a Parts struct holding the typestate
+/// Pin for each of the signals of each PORT instance.
+fn gengpio(outdir: &PathBuf, mcu: &avr_mcu::Mcu) -> std::io::Result<()> {
+    let mut gpio_def = File::create(outdir.join("gpio.rs"))?;
+
+    writeln!(gpio_def, "// GPIO defs for {}", mcu.device.name)?;
+
+    for p in mcu.device.peripherals.iter() {
+        if p.name != "PORT" {
+            continue;
+        }
+        for inst in p.instances.iter() {
+            let mut pins: Vec<(String, u8)> = inst.signals
+                .iter()
+                .filter_map(|sig| sig.index.map(|bitno| (sig.pad.to_ascii_lowercase(), bitno)))
+                .collect();
+            if pins.len() == 0 {
+                continue;
+            }
+            pins.sort_by(|a, b| a.1.cmp(&b.1));
+            pins.dedup_by(|a, b| a.1 == b.1);
+
+            // PORTB -> Portb, used to derive the mcu type names
+            let port_name =
+                RenameRule::PascalCase.apply_to_field(inst.name.to_ascii_lowercase());
+            let flags_name = format!("{}SignalFlags", port_name);
+            let letter = inst.name[4..].to_ascii_lowercase();
+            let parts_name = format!("{}Parts", port_name);
+            let taken_name = format!("{}_TAKEN", inst.name);
+
+            writeln!(gpio_def, "")?;
+            writeln!(gpio_def, "/// {}", inst.name)?;
+            writeln!(gpio_def, "pub struct {};", port_name)?;
+            writeln!(gpio_def, "")?;
+            writeln!(gpio_def, "impl Port for {} {{", port_name)?;
+            for &(method, reg, op) in [
+                ("set_ddr", "ddr", "x | mcu::{flags}::from_bits(mask)"),
+                ("clear_ddr", "ddr", "x - mcu::{flags}::from_bits(mask)"),
+                ("set_port", "port", "x | mcu::{flags}::from_bits(mask)"),
+                ("clear_port", "port", "x - mcu::{flags}::from_bits(mask)"),
+            ].iter()
+            {
+                writeln!(gpio_def, "    #[inline]")?;
+                writeln!(gpio_def, "    fn {}(mask: u8) {{", method)?;
+                writeln!(gpio_def, "        let _cs = CriticalSection::new();")?;
+                writeln!(
+                    gpio_def,
+                    "        unsafe {{ (*mcu::{}.get()).{}{}.modify(|x| {}) }}",
+                    inst.name,
+                    reg,
+                    letter,
+                    op.replace("{flags}", &flags_name)
+                )?;
+                writeln!(gpio_def, "    }}")?;
+            }
+            writeln!(gpio_def, "    #[inline]")?;
+            writeln!(gpio_def, "    fn toggle_port(mask: u8) {{")?;
+            writeln!(gpio_def, "        // Writing a one to PINx toggles PORTx")?;
+            writeln!(
+                gpio_def,
+                "        unsafe {{ (*mcu::{}.get()).pin{}.write(mcu::{}::from_bits(mask)) }}",
+                inst.name,
+                letter,
+                flags_name
+            )?;
+            writeln!(gpio_def, "    }}")?;
+            for &(method, reg) in [("read_port", "port"), ("read_pin", "pin")].iter() {
+                writeln!(gpio_def, "    #[inline]")?;
+                writeln!(gpio_def, "    fn {}(mask: u8) -> bool {{", method)?;
+                writeln!(
+                    gpio_def,
+                    "        unsafe {{ (*mcu::{}.get()).{}{}.read().bits() & mask != 0 }}",
+                    inst.name,
+                    reg,
+                    letter
+                )?;
+                writeln!(gpio_def, "    }}")?;
+            }
+            writeln!(gpio_def, "}}")?;
+            writeln!(gpio_def, "")?;
+
+            writeln!(gpio_def, "/// The pins of {}; obtain via {}::take()", inst.name, port_name)?;
+            writeln!(gpio_def, "pub struct {} {{", parts_name)?;
+            for &(ref pad, bitno) in pins.iter() {
+                writeln!(
+                    gpio_def,
+                    "    pub {}: Pin<{}, N{}, Input<Floating>>,",
+                    pad,
+                    port_name,
+                    bitno
+                )?;
+            }
+            writeln!(gpio_def, "}}")?;
+            writeln!(gpio_def, "")?;
+
+            writeln!(gpio_def, "static mut {}: bool = false;", taken_name)?;
+            writeln!(gpio_def, "")?;
+            writeln!(gpio_def, "impl {} {{", port_name)?;
+            writeln!(gpio_def, "    /// Returns the pins of this port the first time it is called,")?;
+            writeln!(gpio_def, "    /// and None thereafter.  The pins are assumed to be in their")?;
+            writeln!(gpio_def, "    /// reset state of floating inputs.")?;
+            writeln!(gpio_def, "    pub fn take() -> Option<{}> {{", parts_name)?;
+            writeln!(gpio_def, "        let _cs = CriticalSection::new();")?;
+            writeln!(gpio_def, "        unsafe {{")?;
+            writeln!(gpio_def, "            if {} {{", taken_name)?;
+            writeln!(gpio_def, "                return None;")?;
+            writeln!(gpio_def, "            }}")?;
+            writeln!(gpio_def, "            {} = true;", taken_name)?;
+            writeln!(gpio_def, "        }}")?;
+            writeln!(gpio_def, "        Some({} {{", parts_name)?;
+            for &(ref pad, _) in pins.iter() {
+                writeln!(gpio_def, "            {}: Pin::new(),", pad)?;
+            }
+            writeln!(gpio_def, "        }})")?;
+            writeln!(gpio_def, "    }}")?;
+            writeln!(gpio_def, "}}")?;
+        }
+    }
+
+    Ok(())
+}
@@ -6,35 +6,28 @@
 extern crate flutterby;
 
 use flutterby::eventloop::Ticks;
-
-// this is the red LED on most adafruit 32u4 boards
-// (the feather product line)
-use flutterby::mcu::{PortcSignalFlags, PORTC};
-
-pub fn toggle_led(_now: flutterby::eventloop::Ticks) {
-    unsafe {
-        (*PORTC.get()).portc.modify(|mut x| {
-            x ^= PortcSignalFlags::PC7;
-            logln!("LED ", x.bits());
-            x
-        });
-    }
-}
+use flutterby::gpio::Portc;
 
 #[no_mangle]
 pub extern "C" fn main() {
     flutterby::reset_peripherals();
 
-    unsafe {
-        // Configure LED and turn it off
-        (*PORTC.get()).ddrc.modify(|x| x | PortcSignalFlags::PC7);
-        (*PORTC.get()).portc.write(PortcSignalFlags::empty());
-    }
+    // PC7 is the red LED on most adafruit 32u4 boards
+    // (the feather product line)
+    let portc = Portc::take().expect("take PORTC");
+    let mut led = portc.pc7.into_output();
+    led.set_low();
 
     let events = flutterby::eventloop::EventLoop::new();
 
     events
-        .spawn_repeating(toggle_led, Ticks::milliseconds(1000))
+        .spawn_repeating(
+            move |_now| {
+                led.toggle();
+                logln!("LED ", led.is_set_high() as u8);
+            },
+            Ticks::milliseconds(1000),
+        )
         .expect("add led callback");
 
     events.run();
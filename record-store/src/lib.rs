@@ -0,0 +1,460 @@
+//! A small wear-leveled record store for persisting configuration in
+//! byte addressable storage such as an EEPROM.
+//!
+//! This lives in its own crate, free of any AVR specifics, so that it
+//! can be tested on the host against a `MemoryStorage`; run `cargo test`
+//! from this directory.  flutterby implements `Storage` for the on-chip
+//! EEPROM and re-exports these types from its `eeprom` module.
+#![cfg_attr(not(test), no_std)]
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The address range is outside of the storage
+    OutOfRange,
+    /// The record is larger than the slot size allows
+    TooLarge,
+}
+
+/// Byte addressable persistent storage
+pub trait Storage {
+    /// The size of the storage in bytes
+    fn capacity(&self) -> usize;
+
+    /// Fill `buf` with the bytes starting at `addr`
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Write `data` starting at `addr`, blocking until complete.
+    /// Only the bytes that differ from the stored values are written.
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<(), Error>;
+}
+
+/// Returns OutOfRange unless `len` bytes starting at `addr` fit within
+/// `capacity` bytes of storage
+#[inline]
+pub fn check_range(capacity: usize, addr: usize, len: usize) -> Result<(), Error> {
+    if addr > capacity || len > capacity - addr {
+        Err(Error::OutOfRange)
+    } else {
+        Ok(())
+    }
+}
+
+/// A RAM backed Storage, for testing code that uses the record
+/// store without wearing out the EEPROM
+pub struct MemoryStorage<A> {
+    data: A,
+    bytes_written: usize,
+}
+
+impl<A: AsRef<[u8]> + AsMut<[u8]>> MemoryStorage<A> {
+    pub fn new(data: A) -> Self {
+        Self {
+            data,
+            bytes_written: 0,
+        }
+    }
+
+    /// The number of bytes that have actually been changed by writes
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+
+    pub fn into_inner(self) -> A {
+        self.data
+    }
+}
+
+impl<A: AsRef<[u8]> + AsMut<[u8]>> Storage for MemoryStorage<A> {
+    fn capacity(&self) -> usize {
+        self.data.as_ref().len()
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        check_range(self.capacity(), addr, buf.len())?;
+        buf.copy_from_slice(&self.data.as_ref()[addr..addr + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<(), Error> {
+        check_range(self.capacity(), addr, data.len())?;
+        let stored = &mut self.data.as_mut()[addr..addr + data.len()];
+        for (byte, value) in stored.iter_mut().zip(data.iter()) {
+            if *byte != *value {
+                *byte = *value;
+                self.bytes_written += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(crc: u16, data: &[u8]) -> u16 {
+    let mut crc = crc;
+    for byte in data.iter() {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// sequence (2), length (2), crc (2)
+const HEADER_SIZE: usize = 6;
+
+/// The largest record that can be loaded or saved
+pub const MAX_RECORD_SIZE: usize = 256;
+
+struct Header {
+    sequence: u16,
+    len: u16,
+    crc: u16,
+}
+
+impl Header {
+    fn decode(buf: &[u8; HEADER_SIZE]) -> Self {
+        Self {
+            sequence: buf[0] as u16 | (buf[1] as u16) << 8,
+            len: buf[2] as u16 | (buf[3] as u16) << 8,
+            crc: buf[4] as u16 | (buf[5] as u16) << 8,
+        }
+    }
+
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        [
+            self.sequence as u8,
+            (self.sequence >> 8) as u8,
+            self.len as u8,
+            (self.len >> 8) as u8,
+            self.crc as u8,
+            (self.crc >> 8) as u8,
+        ]
+    }
+
+    /// The CRC covers the sequence, length and payload
+    fn compute_crc(sequence: u16, payload: &[u8]) -> u16 {
+        let len = payload.len() as u16;
+        let crc = crc16(
+            0xffff,
+            &[sequence as u8, (sequence >> 8) as u8, len as u8, (len >> 8) as u8],
+        );
+        crc16(crc, payload)
+    }
+}
+
+/// Stores a single variable length record, such as a configuration
+/// blob.  The region of storage is divided into slots and each save
+/// goes to the slot after the most recent one, so that the writes are
+/// spread across the region.  Each slot carries a sequence number and
+/// a CRC so that the most recent intact record is found on load, even
+/// if power was lost part way through a save.
+pub struct RecordStore<S: Storage> {
+    storage: S,
+    base: usize,
+    slot_size: usize,
+    num_slots: usize,
+    /// The slot and sequence number of the most recent valid record
+    current: Option<(usize, u16)>,
+}
+
+impl<S: Storage> RecordStore<S> {
+    /// Use `num_slots` slots of `slot_size` bytes, starting at `base`.
+    /// Each slot holds up to `slot_size - 6` bytes of data.
+    pub fn new(storage: S, base: usize, slot_size: usize, num_slots: usize) -> Result<Self, Error> {
+        if slot_size <= HEADER_SIZE || num_slots == 0 {
+            return Err(Error::TooLarge);
+        }
+        check_range(storage.capacity(), base, slot_size * num_slots)?;
+        let mut store = Self {
+            storage,
+            base,
+            slot_size,
+            num_slots,
+            current: None,
+        };
+        store.current = store.find_current()?;
+        Ok(store)
+    }
+
+    /// Release the underlying storage
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// The largest record that can be saved
+    pub fn max_len(&self) -> usize {
+        let len = self.slot_size - HEADER_SIZE;
+        if len > MAX_RECORD_SIZE {
+            MAX_RECORD_SIZE
+        } else {
+            len
+        }
+    }
+
+    #[inline]
+    fn slot_addr(&self, slot: usize) -> usize {
+        self.base + slot * self.slot_size
+    }
+
+    /// Returns the header of a slot if its contents are intact
+    fn read_valid_header(&self, slot: usize) -> Result<Option<Header>, Error> {
+        let addr = self.slot_addr(slot);
+        let mut header = [0u8; HEADER_SIZE];
+        self.storage.read(addr, &mut header)?;
+        let header = Header::decode(&header);
+        let len = header.len as usize;
+        if len > self.max_len() {
+            return Ok(None);
+        }
+
+        let mut payload = [0u8; MAX_RECORD_SIZE];
+        self.storage.read(addr + HEADER_SIZE, &mut payload[..len])?;
+        if Header::compute_crc(header.sequence, &payload[..len]) != header.crc {
+            return Ok(None);
+        }
+        Ok(Some(header))
+    }
+
+    fn find_current(&self) -> Result<Option<(usize, u16)>, Error> {
+        let mut current: Option<(usize, u16)> = None;
+        for slot in 0..self.num_slots {
+            if let Some(header) = self.read_valid_header(slot)? {
+                let newer = match current {
+                    None => true,
+                    // Allow for the sequence number wrapping around
+                    Some((_, seq)) => (header.sequence.wrapping_sub(seq) as i16) > 0,
+                };
+                if newer {
+                    current = Some((slot, header.sequence));
+                }
+            }
+        }
+        Ok(current)
+    }
+
+    /// Copy the most recently saved record into `buf` and return its
+    /// length, or None if there is no valid record or `buf` is too small.
+    pub fn load(&self, buf: &mut [u8]) -> Option<usize> {
+        let (slot, _) = self.current?;
+        let header = self.read_valid_header(slot).ok()??;
+        let len = header.len as usize;
+        if len > buf.len() {
+            return None;
+        }
+        self.storage
+            .read(self.slot_addr(slot) + HEADER_SIZE, &mut buf[..len])
+            .ok()?;
+        Some(len)
+    }
+
+    /// Save a new version of the record in the next slot
+    pub fn save(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() > self.max_len() {
+            return Err(Error::TooLarge);
+        }
+        let (slot, sequence) = match self.current {
+            Some((slot, sequence)) => ((slot + 1) % self.num_slots, sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let header = Header {
+            sequence,
+            len: data.len() as u16,
+            crc: Header::compute_crc(sequence, data),
+        };
+        let addr = self.slot_addr(slot);
+        // Write the payload before the header; an interrupted save
+        // leaves a slot that fails its CRC check.
+        self.storage.write(addr + HEADER_SIZE, data)?;
+        self.storage.write(addr, &header.encode())?;
+        self.current = Some((slot, sequence));
+        Ok(())
+    }
+
+    /// Invalidate all of the slots
+    pub fn erase(&mut self) -> Result<(), Error> {
+        for slot in 0..self.num_slots {
+            let addr = self.slot_addr(slot);
+            self.storage.write(addr, &[0xff; HEADER_SIZE])?;
+        }
+        self.current = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOT_SIZE: usize = 16;
+    const NUM_SLOTS: usize = 4;
+
+    fn new_store() -> RecordStore<MemoryStorage<[u8; 128]>> {
+        RecordStore::new(MemoryStorage::new([0xff; 128]), 0, SLOT_SIZE, NUM_SLOTS).unwrap()
+    }
+
+    fn load<S: Storage>(store: &RecordStore<S>) -> Option<([u8; 16], usize)> {
+        let mut buf = [0u8; 16];
+        store.load(&mut buf).map(|len| (buf, len))
+    }
+
+    /// Write a slot directly, as a save with the given sequence would
+    fn write_slot<S: Storage>(storage: &mut S, slot: usize, sequence: u16, data: &[u8]) {
+        let addr = slot * SLOT_SIZE;
+        let header = Header {
+            sequence,
+            len: data.len() as u16,
+            crc: Header::compute_crc(sequence, data),
+        };
+        storage.write(addr + HEADER_SIZE, data).unwrap();
+        storage.write(addr, &header.encode()).unwrap();
+    }
+
+    #[test]
+    fn empty_store_has_no_record() {
+        let store = new_store();
+        assert!(load(&store).is_none());
+    }
+
+    #[test]
+    fn save_then_load() {
+        let mut store = new_store();
+        store.save(b"hello").unwrap();
+        let (buf, len) = load(&store).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        // The record survives re-opening the storage
+        let store = RecordStore::new(store.into_inner(), 0, SLOT_SIZE, NUM_SLOTS).unwrap();
+        let (buf, len) = load(&store).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+    }
+
+    #[test]
+    fn load_into_short_buffer_fails() {
+        let mut store = new_store();
+        store.save(b"hello").unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(store.load(&mut buf), None);
+    }
+
+    #[test]
+    fn saves_rotate_through_all_slots() {
+        let mut store = new_store();
+        for i in 0..NUM_SLOTS * 2 {
+            store.save(&[i as u8]).unwrap();
+            assert_eq!(store.current.map(|(slot, _)| slot), Some(i % NUM_SLOTS));
+        }
+
+        let storage = store.into_inner();
+        for slot in 0..NUM_SLOTS {
+            // Each slot holds the second of the two records written to it
+            let expected = (NUM_SLOTS + slot) as u8;
+            assert_eq!(storage.data[slot * SLOT_SIZE + HEADER_SIZE], expected);
+        }
+
+        let store = RecordStore::new(storage, 0, SLOT_SIZE, NUM_SLOTS).unwrap();
+        let (buf, len) = load(&store).unwrap();
+        assert_eq!(&buf[..len], &[(NUM_SLOTS * 2 - 1) as u8]);
+    }
+
+    #[test]
+    fn sequence_number_wraps_around() {
+        let mut storage = MemoryStorage::new([0xff; 128]);
+        write_slot(&mut storage, 0, 0xfffe, b"a");
+        write_slot(&mut storage, 1, 0xffff, b"b");
+
+        let mut store = RecordStore::new(storage, 0, SLOT_SIZE, NUM_SLOTS).unwrap();
+        let (buf, len) = load(&store).unwrap();
+        assert_eq!(&buf[..len], b"b");
+
+        store.save(b"c").unwrap();
+        assert_eq!(store.current, Some((2, 0)));
+
+        // Sequence 0 is newer than 0xffff
+        let store = RecordStore::new(store.into_inner(), 0, SLOT_SIZE, NUM_SLOTS).unwrap();
+        let (buf, len) = load(&store).unwrap();
+        assert_eq!(&buf[..len], b"c");
+    }
+
+    #[test]
+    fn torn_save_falls_back_to_previous_record() {
+        let mut store = new_store();
+        store.save(b"old").unwrap();
+        let mut storage = store.into_inner();
+
+        // The payload of the next save was written, but not its header
+        storage.write(SLOT_SIZE + HEADER_SIZE, b"new").unwrap();
+
+        let store = RecordStore::new(storage, 0, SLOT_SIZE, NUM_SLOTS).unwrap();
+        let (buf, len) = load(&store).unwrap();
+        assert_eq!(&buf[..len], b"old");
+    }
+
+    #[test]
+    fn corrupt_record_falls_back_to_previous_record() {
+        let mut store = new_store();
+        store.save(b"old").unwrap();
+        store.save(b"new").unwrap();
+        let mut storage = store.into_inner();
+
+        storage.write(SLOT_SIZE + HEADER_SIZE, b"N").unwrap();
+
+        let store = RecordStore::new(storage, 0, SLOT_SIZE, NUM_SLOTS).unwrap();
+        let (buf, len) = load(&store).unwrap();
+        assert_eq!(&buf[..len], b"old");
+    }
+
+    #[test]
+    fn erase_removes_all_records() {
+        let mut store = new_store();
+        store.save(b"one").unwrap();
+        store.save(b"two").unwrap();
+        store.erase().unwrap();
+        assert!(load(&store).is_none());
+
+        let store = RecordStore::new(store.into_inner(), 0, SLOT_SIZE, NUM_SLOTS).unwrap();
+        assert!(load(&store).is_none());
+    }
+
+    #[test]
+    fn unchanged_bytes_are_not_rewritten() {
+        let mut storage = MemoryStorage::new([0u8; 8]);
+        storage.write(0, &[1, 2, 3]).unwrap();
+        assert_eq!(storage.bytes_written(), 3);
+        storage.write(0, &[1, 2, 4]).unwrap();
+        assert_eq!(storage.bytes_written(), 4);
+    }
+
+    #[test]
+    fn oversized_record_is_rejected() {
+        let mut store = new_store();
+        assert_eq!(store.max_len(), SLOT_SIZE - HEADER_SIZE);
+        assert_eq!(store.save(&[0; SLOT_SIZE]), Err(Error::TooLarge));
+        assert!(store.save(&[0; SLOT_SIZE - HEADER_SIZE]).is_ok());
+    }
+
+    #[test]
+    fn invalid_layouts_are_rejected() {
+        let storage = MemoryStorage::new([0xff; 128]);
+        assert_eq!(
+            RecordStore::new(storage, 0, SLOT_SIZE, 0).err(),
+            Some(Error::TooLarge)
+        );
+
+        let storage = MemoryStorage::new([0xff; 128]);
+        assert_eq!(
+            RecordStore::new(storage, 0, HEADER_SIZE, NUM_SLOTS).err(),
+            Some(Error::TooLarge)
+        );
+
+        let storage = MemoryStorage::new([0xff; 128]);
+        assert_eq!(
+            RecordStore::new(storage, 64, SLOT_SIZE, 8).err(),
+            Some(Error::OutOfRange)
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Analog to digital converter.
+//!
+//! Conversions can be performed by busy waiting, by sleeping in
+//! `SleepMode::ADCNoiseReduction` until the conversion completes, or
+//! asynchronously via a Future.  The `Adc` keeps the ADC powered for
+//! as long as it exists, so while it is alive and no other peripheral
+//! needs the I/O clock the event loop sleeps in noise reduction mode
+//! rather than power down, whether or not a conversion is in flight.
+//! Drop the `Adc` between readings to allow the deeper sleep.
+//!
+//! ```
+//! let mut adc = Adc::new(Reference::Avcc);
+//! let vcc = adc.read_vcc_millivolts();
+//! events.spawn(adc.read(Channel::Adc7).map(|(adc, value)| { ... }))?;
+//! ```
+use mcu::{AdcAdcsraFlags, AdcAdcsrbFlags, AdcAdmuxFlags, ADC};
+use mutex::interrupt_free;
+use power::{self, PowerHandle};
+use core::ptr::{read_volatile, write_volatile};
+use futures::{Async, Future, Poll};
+use sleep::{self, SleepMode};
+use clock;
+use fcpu;
+
+const ADEN: AdcAdcsraFlags = AdcAdcsraFlags::from_bits(1 << 7);
+const ADSC: AdcAdcsraFlags = AdcAdcsraFlags::from_bits(1 << 6);
+const ADIF: AdcAdcsraFlags = AdcAdcsraFlags::from_bits(1 << 4);
+const ADIE: AdcAdcsraFlags = AdcAdcsraFlags::from_bits(1 << 3);
+
+const MUX5: AdcAdcsrbFlags = AdcAdcsrbFlags::from_bits(1 << 5);
+
+/// The ADC needs a clock between 50kHz and 200kHz for full resolution
+const MAX_ADC_CLOCK: u32 = 200_000;
+
+/// The nominal bandgap reference voltage in millivolts
+const BANDGAP_MV: u32 = 1100;
+
+/// The typical temperature sensor reading at 25 degrees Celsius, from
+/// the datasheet's table of temperature against sensor output.  The
+/// actual offset varies from device to device; see
+/// Adc::set_temperature_offset().
+pub const TYPICAL_TEMPERATURE_OFFSET: u16 = 0x160;
+
+/// Selects the voltage reference
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Reference {
+    /// The voltage applied to the AREF pin
+    Aref = 0,
+    /// AVCC, with an external capacitor on AREF
+    Avcc = 1,
+    /// The internal 2.56V reference, with an external capacitor on AREF
+    Internal2_56V = 3,
+}
+
+impl Reference {
+    #[inline]
+    fn bits(&self) -> AdcAdmuxFlags {
+        AdcAdmuxFlags::from_bits((*self as u8) << 6)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Gain {
+    X1,
+    X10,
+    X40,
+    X200,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Adc0,
+    Adc1,
+    Adc4,
+    Adc5,
+    Adc6,
+    Adc7,
+    Adc8,
+    Adc9,
+    Adc10,
+    Adc11,
+    Adc12,
+    Adc13,
+    /// The 1.1V bandgap reference
+    Bandgap,
+    Ground,
+    /// The on-chip temperature sensor
+    Temperature,
+    /// The amplified difference between two of the ADC pins.
+    /// Only some combinations are supported by the hardware: the
+    /// negative input must be ADC0 or ADC1 and the positive input
+    /// one of ADC0, ADC1 or ADC4-ADC7.
+    Differential {
+        positive: u8,
+        negative: u8,
+        gain: Gain,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The differential channel combination is not supported
+    InvalidChannel,
+}
+
+impl Channel {
+    /// Returns the MUX5:0 value for the channel
+    fn mux(&self) -> Result<u8, Error> {
+        use self::Channel::*;
+        Ok(match *self {
+            Adc0 => 0x00,
+            Adc1 => 0x01,
+            Adc4 => 0x04,
+            Adc5 => 0x05,
+            Adc6 => 0x06,
+            Adc7 => 0x07,
+            Bandgap => 0x1e,
+            Ground => 0x1f,
+            Adc8 => 0x20,
+            Adc9 => 0x21,
+            Adc10 => 0x22,
+            Adc11 => 0x23,
+            Adc12 => 0x24,
+            Adc13 => 0x25,
+            Temperature => 0x27,
+            Differential {
+                positive,
+                negative,
+                gain,
+            } => match (positive, negative, gain) {
+                (1, 0, Gain::X10) => 0x09,
+                (1, 0, Gain::X40) => 0x26,
+                (1, 0, Gain::X200) => 0x0b,
+                (0, 1, Gain::X1) => 0x10,
+                (4...7, 1, Gain::X1) => 0x10 + positive,
+                (4...7, 0, Gain::X10) => 0x28 + positive - 4,
+                (4...7, 1, Gain::X10) => 0x2c + positive - 4,
+                (4...7, 0, Gain::X40) => 0x30 + positive - 4,
+                (4...7, 1, Gain::X40) => 0x34 + positive - 4,
+                (4...7, 0, Gain::X200) => 0x38 + positive - 4,
+                (4...7, 1, Gain::X200) => 0x3c + positive - 4,
+                _ => return Err(Error::InvalidChannel),
+            },
+        })
+    }
+}
+
+/// Returns the ADPS bits that give the fastest ADC clock that is
+/// within spec for the current CPU frequency
+fn prescaler_bits() -> AdcAdcsraFlags {
+    let cpu = clock::frequency();
+    let mut adps = 1;
+    while adps < 7 && (cpu >> adps) > MAX_ADC_CLOCK {
+        adps += 1;
+    }
+    AdcAdcsraFlags::from_bits(adps)
+}
+
+/// The result of the most recent interrupt driven conversion
+static mut RESULT: Option<u16> = None;
+
+fn adc_interrupt() {
+    unsafe {
+        write_volatile(&mut RESULT, Some((*ADC.get()).adc.read()));
+    }
+    sleep::set_event_pending();
+}
+
+irq_handler!(ADC, adc_interrupt);
+
+pub struct Adc {
+    _power: PowerHandle,
+    reference: Reference,
+    /// The temperature sensor reading at 25 degrees Celsius
+    temperature_offset: u16,
+}
+
+impl Adc {
+    /// Enable the ADC using the specified reference for conversions
+    /// of the external channels.
+    pub fn new(reference: Reference) -> Self {
+        let power = power::acquire(power::Peripheral::Adc);
+        unsafe {
+            (*ADC.get()).adcsra.write(ADEN | ADIF | prescaler_bits());
+        }
+        Self {
+            _power: power,
+            reference,
+            temperature_offset: TYPICAL_TEMPERATURE_OFFSET,
+        }
+    }
+
+    /// Select the channel and reference for the next conversion
+    fn select(&self, channel: Channel, reference: Reference) -> Result<(), Error> {
+        let mux = channel.mux()?;
+        unsafe {
+            let adc = &(*ADC.get());
+            adc.admux
+                .write(reference.bits() | AdcAdmuxFlags::from_bits(mux & 0x1f));
+            adc.adcsrb.modify(|x| {
+                if mux & 0x20 != 0 {
+                    x | MUX5
+                } else {
+                    x - MUX5
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn convert_blocking(&mut self) -> u16 {
+        unsafe {
+            let adc = &(*ADC.get());
+            adc.adcsra.modify(|x| x | ADSC);
+            while (adc.adcsra.read() & ADSC) == ADSC {}
+            adc.adc.read()
+        }
+    }
+
+    fn convert_noise_reduced(&mut self) -> u16 {
+        unsafe {
+            write_volatile(&mut RESULT, None);
+            // Entering noise reduction mode starts the conversion
+            (*ADC.get()).adcsra.modify(|x| x | ADIE);
+        }
+        let result = loop {
+            if let Some(result) = unsafe { read_volatile(&RESULT) } {
+                break result;
+            }
+            sleep::wait_for_event_in_mode(SleepMode::ADCNoiseReduction);
+        };
+        unsafe {
+            (*ADC.get()).adcsra.modify(|x| x - ADIE);
+        }
+        result
+    }
+
+    /// Perform a conversion, busy waiting until it completes
+    pub fn read_blocking(&mut self, channel: Channel) -> Result<u16, Error> {
+        self.select(channel, self.reference)?;
+        Ok(self.convert_blocking())
+    }
+
+    /// Perform a conversion while sleeping in noise reduction mode.
+    /// Other interrupts may wake the MCU early, in which case it goes
+    /// back to sleep until the conversion is complete.
+    pub fn read_noise_reduced(&mut self, channel: Channel) -> Result<u16, Error> {
+        self.select(channel, self.reference)?;
+        Ok(self.convert_noise_reduced())
+    }
+
+    /// Returns a Future that performs a conversion using the ADC
+    /// interrupt and resolves to the Adc and the converted value.
+    pub fn read(self, channel: Channel) -> Conversion {
+        Conversion {
+            adc: Some(self),
+            channel,
+            started: false,
+        }
+    }
+
+    /// Set the temperature sensor reading at 25 degrees Celsius for
+    /// this particular device, as measured with read_temperature_raw().
+    /// Defaults to TYPICAL_TEMPERATURE_OFFSET.
+    pub fn set_temperature_offset(&mut self, offset: u16) {
+        self.temperature_offset = offset;
+    }
+
+    /// Returns the uncalibrated temperature sensor reading
+    pub fn read_temperature_raw(&mut self) -> u16 {
+        self.select(Channel::Temperature, Reference::Internal2_56V)
+            .expect("temperature channel is valid");
+        // The reference takes a moment to settle; discard the first
+        // conversion after switching.
+        fcpu::busy_wait_ms(1);
+        self.convert_noise_reduced();
+        self.convert_noise_reduced()
+    }
+
+    /// Measure the chip temperature in degrees Celsius.  Without a
+    /// calibrated offset this is only accurate to around 10 degrees.
+    pub fn read_temperature(&mut self) -> i16 {
+        // The sensor output rises by approximately 1 LSB per degree
+        // from the reading at 25 degrees
+        self.read_temperature_raw() as i16 - self.temperature_offset as i16 + 25
+    }
+
+    /// Measure the supply voltage in millivolts by converting the
+    /// bandgap reference relative to AVCC.
+    pub fn read_vcc_millivolts(&mut self) -> u16 {
+        self.select(Channel::Bandgap, Reference::Avcc)
+            .expect("bandgap channel is valid");
+        fcpu::busy_wait_ms(1);
+        self.convert_noise_reduced();
+        let raw = self.convert_noise_reduced() as u32;
+        if raw == 0 {
+            return 0;
+        }
+        (BANDGAP_MV * 1024 / raw) as u16
+    }
+
+    /// Measure the battery voltage in millivolts.  The feather32u4
+    /// has a 2:1 divider from the battery to A9 (ADC12).
+    #[cfg(feature = "feather32u4")]
+    pub fn read_battery_millivolts(&mut self) -> u16 {
+        let vcc = self.read_vcc_millivolts() as u32;
+        self.select(Channel::Adc12, Reference::Avcc)
+            .expect("ADC12 is valid");
+        fcpu::busy_wait_ms(1);
+        self.convert_noise_reduced();
+        let raw = self.convert_noise_reduced() as u32;
+        (raw * 2 * vcc / 1024) as u16
+    }
+}
+
+impl Drop for Adc {
+    fn drop(&mut self) {
+        unsafe {
+            (*ADC.get()).adcsra.write(AdcAdcsraFlags::empty());
+        }
+    }
+}
+
+pub struct Conversion {
+    adc: Option<Adc>,
+    channel: Channel,
+    started: bool,
+}
+
+impl Future for Conversion {
+    type Item = (Adc, u16);
+    type Error = (Adc, Error);
+
+    fn poll(&mut self) -> Poll<(Adc, u16), (Adc, Error)> {
+        if !self.started {
+            let adc = self.adc.take().expect("polled after completion");
+            if let Err(err) = adc.select(self.channel, adc.reference) {
+                return Err((adc, err));
+            }
+            interrupt_free(|_cs| unsafe {
+                write_volatile(&mut RESULT, None);
+                (*ADC.get()).adcsra.modify(|x| x | ADIE | ADSC);
+            });
+            self.adc = Some(adc);
+            self.started = true;
+        }
+
+        match unsafe { read_volatile(&RESULT) } {
+            Some(value) => {
+                unsafe {
+                    (*ADC.get()).adcsra.modify(|x| x - ADIE);
+                }
+                Ok(Async::Ready((
+                    self.adc.take().expect("polled after completion"),
+                    value,
+                )))
+            }
+            None => Ok(Async::NotReady),
+        }
+    }
+}
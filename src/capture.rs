@@ -0,0 +1,328 @@
+//! Measure the time between edges on the ICP1 (PD4) or ICP3 (PC7) pins
+//! using the input capture unit of Timer1 or Timer3.
+//!
+//! The capture interrupt timestamps each edge in hardware, so the
+//! measurements are not affected by interrupt latency.  The timer
+//! overflow interrupt extends the 16-bit counter to 32 bits so that
+//! long intervals can be measured, and the intervals are queued in a
+//! ring buffer until the event loop collects them via the Stream impl.
+//!
+//! The Capture owns the timer, so use a different TickSource for the
+//! event loop.
+//!
+//! ```
+//! let ir = Capture::new(Input::Icp1, ClockSource::Prescale64, Measure::PulseWidth).unwrap();
+//! events.spawn(ir.for_each(|interval| {
+//!     logln!("ticks ", interval.ticks);
+//!     Ok(())
+//! }))?;
+//! ```
+use mcu::{Tc1Tccr1bFlags, Tc1Tifr1Flags, PortdSignalFlags, PORTD, TC1};
+#[cfg(AVR_TC3)]
+use mcu::{Tc3Tccr3bFlags, Tc3Tifr3Flags, PortcSignalFlags, PORTC, TC3};
+use mutex::{interrupt_free, Mutex};
+use ringbuffer::RingBuffer;
+use futures::{Async, Poll, Stream};
+use clock;
+use sleep;
+use timer1;
+#[cfg(AVR_TC3)]
+use timer3;
+pub use timer1::{CaptureEdge, ClockSource};
+
+/// Selects the input capture pin and the timer that serves it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    /// PD4, using Timer1
+    Icp1,
+    /// PC7, using Timer3
+    #[cfg(AVR_TC3)]
+    Icp3,
+}
+
+/// What to measure
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Measure {
+    /// The time between successive edges of the same polarity.
+    /// Useful for frequency measurement.
+    Period(CaptureEdge),
+    /// The time between successive edges of alternating polarity.
+    /// An interval ending on a falling edge is the duration that the
+    /// input was high, and vice versa.  Useful for decoding IR remote
+    /// and rotary encoder pulse trains.
+    PulseWidth,
+}
+
+/// The time between two captured edges
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Interval {
+    /// The edge that ended this interval
+    pub edge: CaptureEdge,
+    /// The duration in timer ticks
+    pub ticks: u32,
+}
+
+const CAPACITY: usize = 16;
+const EMPTY: Interval = Interval {
+    edge: CaptureEdge::Falling,
+    ticks: 0,
+};
+
+struct State {
+    /// The upper 16 bits of the extended counter
+    overflows: u16,
+    /// Timestamp of the previous edge
+    last: Option<u32>,
+    toggle_edge: bool,
+    /// Number of intervals discarded because the buffer was full
+    dropped: u16,
+    intervals: RingBuffer<Interval, [Interval; CAPACITY]>,
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            overflows: 0,
+            last: None,
+            toggle_edge: false,
+            dropped: 0,
+            intervals: RingBuffer::new([EMPTY; CAPACITY]),
+        }
+    }
+
+    fn reset(&mut self, toggle_edge: bool) {
+        self.overflows = 0;
+        self.last = None;
+        self.toggle_edge = toggle_edge;
+        self.dropped = 0;
+        self.intervals.clear();
+    }
+
+    /// Called from the capture interrupt.  `overflow_pending` indicates
+    /// that the counter wrapped but the overflow interrupt has not yet
+    /// been serviced.
+    fn capture(&mut self, icr: u16, overflow_pending: bool, edge: CaptureEdge) {
+        // If the overflow is pending and the captured value is small,
+        // the capture happened after the wrap.
+        let overflows = if overflow_pending && icr < 0x8000 {
+            self.overflows.wrapping_add(1)
+        } else {
+            self.overflows
+        };
+        let stamp = ((overflows as u32) << 16) | icr as u32;
+
+        if let Some(last) = self.last {
+            let interval = Interval {
+                edge,
+                ticks: stamp.wrapping_sub(last),
+            };
+            if self.intervals.push(interval).is_err() {
+                self.dropped = self.dropped.saturating_add(1);
+            }
+            sleep::set_event_pending();
+        }
+        self.last = Some(stamp);
+    }
+}
+
+static TIMER1_STATE: Mutex<State> = Mutex::new(State::new());
+#[cfg(AVR_TC3)]
+static TIMER3_STATE: Mutex<State> = Mutex::new(State::new());
+
+fn timer1_capture() {
+    let mut state = TIMER1_STATE.lock();
+    unsafe {
+        let tc1 = &(*TC1.get());
+        let icr = tc1.icr1.read();
+        let pending = (tc1.tifr1.read() & Tc1Tifr1Flags::TOV1) == Tc1Tifr1Flags::TOV1;
+        let edge = if (tc1.tccr1b.read() & Tc1Tccr1bFlags::ICES1) == Tc1Tccr1bFlags::ICES1 {
+            CaptureEdge::Rising
+        } else {
+            CaptureEdge::Falling
+        };
+        state.capture(icr, pending, edge);
+
+        if state.toggle_edge {
+            tc1.tccr1b.modify(|x| x ^ Tc1Tccr1bFlags::ICES1);
+            // Changing the edge may trigger a capture; discard it
+            tc1.tifr1.write(Tc1Tifr1Flags::ICF1);
+        }
+    }
+}
+
+fn timer1_overflow() {
+    let mut state = TIMER1_STATE.lock();
+    state.overflows = state.overflows.wrapping_add(1);
+}
+
+#[cfg(AVR_TC3)]
+fn timer3_capture() {
+    let mut state = TIMER3_STATE.lock();
+    unsafe {
+        let tc3 = &(*TC3.get());
+        let icr = tc3.icr3.read();
+        let pending = (tc3.tifr3.read() & Tc3Tifr3Flags::TOV3) == Tc3Tifr3Flags::TOV3;
+        let edge = if (tc3.tccr3b.read() & Tc3Tccr3bFlags::ICES3) == Tc3Tccr3bFlags::ICES3 {
+            CaptureEdge::Rising
+        } else {
+            CaptureEdge::Falling
+        };
+        state.capture(icr, pending, edge);
+
+        if state.toggle_edge {
+            tc3.tccr3b.modify(|x| x ^ Tc3Tccr3bFlags::ICES3);
+            // Changing the edge may trigger a capture; discard it
+            tc3.tifr3.write(Tc3Tifr3Flags::ICF3);
+        }
+    }
+}
+
+#[cfg(AVR_TC3)]
+fn timer3_overflow() {
+    let mut state = TIMER3_STATE.lock();
+    state.overflows = state.overflows.wrapping_add(1);
+}
+
+enum TimerHandle {
+    Timer1(timer1::Handle),
+    #[cfg(AVR_TC3)]
+    Timer3(timer3::Handle),
+}
+
+pub struct Capture {
+    input: Input,
+    timer: TimerHandle,
+    divisor: Option<u32>,
+}
+
+impl Capture {
+    /// Configure the timer associated with `input` in normal mode,
+    /// clocked from `clock_source`, and start capturing edges.
+    /// The noise canceler is enabled, so an edge must be stable for
+    /// 4 CPU cycles to be recognized.  Returns None if something else
+    /// already owns Timer1 when `input` is Icp1.
+    pub fn new(input: Input, clock_source: ClockSource, measure: Measure) -> Option<Self> {
+        let (first_edge, toggle_edge) = match measure {
+            Measure::Period(edge) => (edge, false),
+            Measure::PulseWidth => (CaptureEdge::Rising, true),
+        };
+
+        let timer = match input {
+            Input::Icp1 => {
+                if !timer1::claim() {
+                    return None;
+                }
+                TIMER1_STATE.lock().reset(toggle_edge);
+                unsafe {
+                    (*PORTD.get()).ddrd.modify(|x| x - PortdSignalFlags::PD4);
+                }
+                timer1::set_interrupt_handler(timer1::Interrupt::InputCapture, Some(timer1_capture));
+                timer1::set_interrupt_handler(timer1::Interrupt::Overflow, Some(timer1_overflow));
+                TimerHandle::Timer1(
+                    timer1::Timer::new()
+                        .waveform_generation_mode(timer1::WaveformGenerationMode::Normal)
+                        .clock_source(clock_source)
+                        .input_capture_edge(first_edge)
+                        .input_capture_noise_canceler(true)
+                        .interrupt(timer1::Interrupt::InputCapture)
+                        .interrupt(timer1::Interrupt::Overflow)
+                        .configure(),
+                )
+            }
+            #[cfg(AVR_TC3)]
+            Input::Icp3 => {
+                TIMER3_STATE.lock().reset(toggle_edge);
+                unsafe {
+                    (*PORTC.get()).ddrc.modify(|x| x - PortcSignalFlags::PC7);
+                }
+                timer3::set_interrupt_handler(timer3::Interrupt::InputCapture, Some(timer3_capture));
+                timer3::set_interrupt_handler(timer3::Interrupt::Overflow, Some(timer3_overflow));
+                TimerHandle::Timer3(
+                    timer3::Timer::new()
+                        .waveform_generation_mode(timer3::WaveformGenerationMode::Normal)
+                        .clock_source(clock_source)
+                        .input_capture_edge(first_edge)
+                        .input_capture_noise_canceler(true)
+                        .interrupt(timer3::Interrupt::InputCapture)
+                        .interrupt(timer3::Interrupt::Overflow)
+                        .configure(),
+                )
+            }
+        };
+
+        Some(Self {
+            input,
+            timer,
+            divisor: clock_source.divisor(),
+        })
+    }
+
+    fn state(&self) -> &'static Mutex<State> {
+        match self.input {
+            Input::Icp1 => &TIMER1_STATE,
+            #[cfg(AVR_TC3)]
+            Input::Icp3 => &TIMER3_STATE,
+        }
+    }
+
+    /// Returns the number of intervals that were discarded because
+    /// they were not collected from the stream quickly enough.
+    pub fn dropped(&self) -> u16 {
+        self.state().lock().dropped
+    }
+
+    /// Convert a number of ticks to microseconds based on the current
+    /// CPU frequency.  Returns None if the timer is clocked from
+    /// an external source.
+    pub fn micros(&self, ticks: u32) -> Option<u32> {
+        let hz = clock::frequency() / self.divisor?;
+        if hz >= 1_000_000 {
+            Some(ticks / (hz / 1_000_000))
+        } else {
+            Some(ticks.saturating_mul(1_000_000 / hz))
+        }
+    }
+
+    /// Returns the frequency in Hz corresponding to a period measured
+    /// in ticks.  Returns None if the timer is clocked from an external
+    /// source or if `ticks` is zero.
+    pub fn frequency(&self, ticks: u32) -> Option<u32> {
+        if ticks == 0 {
+            return None;
+        }
+        Some(clock::frequency() / self.divisor? / ticks)
+    }
+}
+
+impl Stream for Capture {
+    type Item = Interval;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Interval>, ()> {
+        match self.state().lock().intervals.pop() {
+            Some(interval) => Ok(Async::Ready(Some(interval))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        interrupt_free(|_cs| match self.timer {
+            TimerHandle::Timer1(ref timer) => {
+                timer.disable_interrupt(timer1::Interrupt::InputCapture);
+                timer.disable_interrupt(timer1::Interrupt::Overflow);
+                timer1::set_interrupt_handler(timer1::Interrupt::InputCapture, None);
+                timer1::set_interrupt_handler(timer1::Interrupt::Overflow, None);
+                timer1::release();
+            }
+            #[cfg(AVR_TC3)]
+            TimerHandle::Timer3(ref timer) => {
+                timer.disable_interrupt(timer3::Interrupt::InputCapture);
+                timer.disable_interrupt(timer3::Interrupt::Overflow);
+                timer3::set_interrupt_handler(timer3::Interrupt::InputCapture, None);
+                timer3::set_interrupt_handler(timer3::Interrupt::Overflow, None);
+            }
+        });
+    }
+}
@@ -0,0 +1,93 @@
+//! System clock prescaler control.
+//! The system clock is derived from the oscillator (whose frequency is
+//! fcpu::F_CPU) divided by the prescaler in CLKPR.  The prescaler can be
+//! changed at runtime, for example to let idle firmware drop down to 1MHz,
+//! so code that needs to know the actual CPU frequency should call
+//! frequency() rather than using F_CPU directly.
+use mcu::{CpuClkprFlags, CPU};
+use fcpu::F_CPU;
+use mutex::interrupt_free;
+use core::ptr::{read_volatile, write_volatile};
+
+/// The value of the CLKPS bits selects the divisor
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Prescaler {
+    Div1 = 0,
+    Div2 = 1,
+    Div4 = 2,
+    Div8 = 3,
+    Div16 = 4,
+    Div32 = 5,
+    Div64 = 6,
+    Div128 = 7,
+    Div256 = 8,
+}
+
+impl Prescaler {
+    #[inline]
+    fn bits(&self) -> CpuClkprFlags {
+        CpuClkprFlags::from_bits(*self as u8)
+    }
+
+    /// Returns log2 of the divisor
+    #[inline]
+    pub fn shift(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Returns the prescaler that divides F_CPU down to exactly `hz`,
+    /// if there is one.
+    pub fn for_frequency(hz: u32) -> Option<Prescaler> {
+        use self::Prescaler::*;
+        for p in [
+            Div1, Div2, Div4, Div8, Div16, Div32, Div64, Div128, Div256
+        ].iter()
+        {
+            if F_CPU >> p.shift() == hz {
+                return Some(*p);
+            }
+        }
+        None
+    }
+}
+
+static mut PRESCALER: Prescaler = Prescaler::Div1;
+
+/// Called by the main startup code, so you won't generally need to call this.
+/// Runs the CPU at the full oscillator frequency.
+pub fn initialize() {
+    set_prescaler(Prescaler::Div1);
+}
+
+/// Change the system clock prescaler.  Anything that has been configured
+/// based on the CPU frequency (timers, baud rates and so on) will need to be
+/// reconfigured after this returns.  The event loop does this for its tick
+/// timer automatically.
+pub fn set_prescaler(prescaler: Prescaler) {
+    interrupt_free(|_cs| unsafe {
+        let cpu = &(*CPU.get());
+        // The new value must be written within 4 cycles of
+        // setting the change enable bit.
+        cpu.clkpr.write(CpuClkprFlags::CLKPCE);
+        cpu.clkpr.write(prescaler.bits());
+        write_volatile(&mut PRESCALER, prescaler);
+    });
+}
+
+/// Change the system clock to run at `hz`.  Fails if `hz` cannot be
+/// reached by dividing F_CPU by a power of two.
+pub fn set_frequency(hz: u32) -> Result<(), ()> {
+    let prescaler = Prescaler::for_frequency(hz).ok_or(())?;
+    set_prescaler(prescaler);
+    Ok(())
+}
+
+/// Returns the current system clock prescaler
+pub fn prescaler() -> Prescaler {
+    unsafe { read_volatile(&PRESCALER) }
+}
+
+/// Returns the current CPU frequency in Hz
+pub fn frequency() -> u32 {
+    F_CPU >> prescaler().shift()
+}
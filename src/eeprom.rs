@@ -0,0 +1,180 @@
+//! Access to the on-chip EEPROM, and a small wear-leveled record
+//! store for persisting configuration.
+//!
+//! Writing a byte takes around 3.4ms, so the writes only touch bytes
+//! whose value actually changes.  `Eeprom::update()` returns a Future
+//! that is woken by the EE_READY interrupt as each byte completes,
+//! allowing the event loop to keep running during long writes.
+//!
+//! The record store lives in the `record-store` crate, written against
+//! the `Storage` trait rather than the EEPROM directly, so that it can
+//! be tested against a `MemoryStorage` on the host.  Its types are
+//! re-exported here.
+//!
+//! ```
+//! let mut config = RecordStore::new(Eeprom::new(), 0, 64, 16).unwrap();
+//! let mut buf = [0u8; 48];
+//! if let Some(len) = config.load(&mut buf) { ... }
+//! config.save(&buf[..len]).unwrap();
+//! ```
+use mcu::{EepromEecrFlags, EEPROM};
+use mutex::interrupt_free;
+use futures::{Async, Future, Poll};
+use sleep;
+use record_store::check_range;
+pub use record_store::{Error, MemoryStorage, RecordStore, Storage, MAX_RECORD_SIZE};
+
+const EERIE: EepromEecrFlags = EepromEecrFlags::from_bits(1 << 3);
+const EEMPE: EepromEecrFlags = EepromEecrFlags::from_bits(1 << 2);
+const EEPE: EepromEecrFlags = EepromEecrFlags::from_bits(1 << 1);
+const EERE: EepromEecrFlags = EepromEecrFlags::from_bits(1 << 0);
+
+/// The size of the EEPROM in bytes
+pub const SIZE: usize = 1024;
+
+#[inline]
+pub(crate) fn is_busy() -> bool {
+    unsafe { ((*EEPROM.get()).eecr.read() & EEPE) == EEPE }
+}
+
+fn read_byte(addr: u16) -> u8 {
+    while is_busy() {}
+    unsafe {
+        let eeprom = &(*EEPROM.get());
+        eeprom.eear.write(addr);
+        eeprom.eecr.write(EERE);
+        eeprom.eedr.read()
+    }
+}
+
+/// Start an erase and write of a single byte.  The caller must
+/// ensure that no write is in progress.
+fn start_write(addr: u16, byte: u8, interrupt: bool) {
+    interrupt_free(|_cs| unsafe {
+        let eeprom = &(*EEPROM.get());
+        eeprom.eear.write(addr);
+        eeprom.eedr.write(byte);
+        let eerie = if interrupt {
+            EERIE
+        } else {
+            EepromEecrFlags::empty()
+        };
+        // EEPE must be set within four cycles of EEMPE
+        eeprom.eecr.write(eerie | EEMPE);
+        eeprom.eecr.write(eerie | EEMPE | EEPE);
+    });
+}
+
+fn ee_ready() {
+    // The interrupt fires continuously while EEPE is clear, so turn it
+    // off and let the Update future start the next byte.
+    unsafe {
+        (*EEPROM.get()).eecr.modify(|x| x - EERIE);
+    }
+    sleep::set_event_pending();
+}
+
+irq_handler!(EE_READY, ee_ready);
+
+/// Handle to the on-chip EEPROM
+pub struct Eeprom {
+    _private: (),
+}
+
+impl Eeprom {
+    pub fn new() -> Self {
+        Self { _private: () }
+    }
+
+    pub fn read_byte(&self, addr: u16) -> Result<u8, Error> {
+        check_range(SIZE, addr as usize, 1)?;
+        Ok(read_byte(addr))
+    }
+
+    /// Write a byte if it differs from the stored value, blocking
+    /// until complete
+    pub fn update_byte(&mut self, addr: u16, byte: u8) -> Result<(), Error> {
+        check_range(SIZE, addr as usize, 1)?;
+        if read_byte(addr) != byte {
+            start_write(addr, byte, false);
+            while is_busy() {}
+        }
+        Ok(())
+    }
+
+    /// Returns a Future that writes the bytes of `buf` that differ from
+    /// the stored values, starting at `addr`, and then resolves to the
+    /// Eeprom and the buffer.
+    pub fn update<B: AsRef<[u8]>>(self, addr: u16, buf: B) -> Update<B> {
+        let error = check_range(SIZE, addr as usize, buf.as_ref().len()).err();
+        Update {
+            eeprom: Some(self),
+            buf: Some(buf),
+            addr,
+            pos: 0,
+            error,
+        }
+    }
+}
+
+impl Storage for Eeprom {
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        check_range(SIZE, addr, buf.len())?;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = read_byte((addr + i) as u16);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: usize, data: &[u8]) -> Result<(), Error> {
+        check_range(SIZE, addr, data.len())?;
+        for (i, byte) in data.iter().enumerate() {
+            self.update_byte((addr + i) as u16, *byte)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Update<B> {
+    eeprom: Option<Eeprom>,
+    buf: Option<B>,
+    addr: u16,
+    pos: usize,
+    error: Option<Error>,
+}
+
+impl<B: AsRef<[u8]>> Future for Update<B> {
+    type Item = (Eeprom, B);
+    type Error = (Eeprom, Error);
+
+    fn poll(&mut self) -> Poll<(Eeprom, B), (Eeprom, Error)> {
+        if let Some(error) = self.error.take() {
+            return Err((self.eeprom.take().expect("polled after completion"), error));
+        }
+        if is_busy() {
+            return Ok(Async::NotReady);
+        }
+
+        {
+            let data = self.buf.as_ref().expect("polled after completion").as_ref();
+            while self.pos < data.len() {
+                let addr = self.addr + self.pos as u16;
+                let byte = data[self.pos];
+                self.pos += 1;
+                if read_byte(addr) != byte {
+                    start_write(addr, byte, true);
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+
+        Ok(Async::Ready((
+            self.eeprom.take().expect("polled after completion"),
+            self.buf.take().unwrap(),
+        )))
+    }
+}
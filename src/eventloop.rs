@@ -1,6 +1,6 @@
 use arrayvec::{ArrayVec, CapacityError};
 use mutex::Mutex;
-use fcpu::F_CPU;
+use clock;
 use core::ptr::{read_volatile, write_volatile};
 use timer1;
 use sleep;
@@ -9,12 +9,27 @@ use core::cell::RefCell;
 use futures::{Async, Future};
 use futures::Stream;
 use core::ops;
-
-const DESIRED_HZ_TIM1: f64 = 50.0;
-const TIM1_PRESCALER: u64 = 1024;
-const INTERRUPT_EVERY_1_HZ_1024_PRESCALER: u16 =
-    ((F_CPU as f64 / (DESIRED_HZ_TIM1 * TIM1_PRESCALER as f64)) as u64 - 1) as u16;
-const TICKS_PER_MS: u16 = (1000.0 / DESIRED_HZ_TIM1) as u16;
+#[cfg(AVR_WDT)]
+use supervisor::Supervisor;
+#[cfg(all(AVR_TC3, AVR_PORTC))]
+use tone::{Melody, Note, Tone};
+
+const TICKS_HZ: u16 = 50;
+const TICKS_PER_MS: u16 = 1000 / TICKS_HZ;
+/// The interval between ticks in milliseconds
+pub(crate) const TICK_PERIOD_MS: u16 = 1000 / TICKS_HZ;
+
+/// A hardware timer that can drive the event loop's notion of time.
+pub trait TickSource {
+    /// Configure the hardware to call `tick` from its interrupt handler
+    /// approximately `hz` times per second, based on the current
+    /// clock::frequency().  This is called again if the CPU frequency
+    /// is changed while the event loop is running.
+    fn start(&mut self, hz: u16, tick: fn());
+
+    /// Stop generating ticks and release the hardware.
+    fn stop(&mut self);
+}
 
 #[derive(Copy, Clone, Default, Debug, PartialOrd, Ord, Eq, PartialEq)]
 pub struct Ticks {
@@ -45,6 +60,13 @@ impl Ticks {
     pub fn is_zero(&self) -> bool {
         self.ticks == 0
     }
+
+    /// Returns the number of ticks from `earlier` to `self`, allowing
+    /// for the tick counter having wrapped around in between.
+    #[inline]
+    pub fn wrapping_sub(self, earlier: Ticks) -> Ticks {
+        Ticks::new(self.ticks.wrapping_sub(earlier.ticks))
+    }
 }
 
 impl ops::Sub for Ticks {
@@ -133,20 +155,31 @@ enum CoreSlot {
     Occupied(Option<SlotEntry>),
 }
 
-struct EventLoopCore {
+struct EventLoopCore<T: TickSource> {
     slots: ArrayVec<[CoreSlot; 8]>,
     next_slot: usize,
+    tick_source: T,
+    #[cfg(AVR_WDT)]
+    supervisor: Option<Supervisor>,
 }
 
-impl EventLoopCore {
+impl<T: TickSource> EventLoopCore<T> {
     fn configure_timer(&mut self) {
-        timer1::Timer::new()
-            .waveform_generation_mode(
-                timer1::WaveformGenerationMode::ClearOnTimerMatchOutputCompare,
-            )
-            .clock_source(timer1::ClockSource::Prescale1024)
-            .output_compare_1(INTERRUPT_EVERY_1_HZ_1024_PRESCALER)
-            .configure();
+        self.tick_source.start(TICKS_HZ, tick);
+    }
+
+    #[cfg(AVR_WDT)]
+    fn start_supervisor(&mut self) {
+        if let Some(ref mut supervisor) = self.supervisor {
+            supervisor.start();
+        }
+    }
+
+    #[cfg(AVR_WDT)]
+    fn supervise(&mut self, elapsed_ticks: Ticks) {
+        if let Some(ref mut supervisor) = self.supervisor {
+            supervisor.supervise(elapsed_ticks);
+        }
     }
 
     fn add_slot(&mut self, slot: SlotEntry) -> Result<(), CapacityError<CoreSlot>> {
@@ -168,16 +201,37 @@ impl EventLoopCore {
     }
 }
 
-pub struct EventLoop {
-    inner: Mutex<EventLoopCore>,
+impl<T: TickSource> Drop for EventLoopCore<T> {
+    fn drop(&mut self) {
+        self.tick_source.stop();
+    }
+}
+
+pub struct EventLoop<T: TickSource = timer1::Ticker> {
+    inner: Mutex<EventLoopCore<T>>,
 }
 
-impl EventLoop {
+impl EventLoop<timer1::Ticker> {
+    /// Create an event loop that is driven by Timer1.  Panics if
+    /// Timer1 is already in use.
     pub fn new() -> Self {
+        Self::with_tick_source(timer1::Ticker::new().expect("Timer1 is already in use"))
+    }
+}
+
+impl<T: TickSource> EventLoop<T> {
+    /// Create an event loop that is driven by the specified tick source.
+    /// This allows the application to choose which hardware timer is
+    /// used for scheduling so that the others can be used for PWM and
+    /// so on.
+    pub fn with_tick_source(tick_source: T) -> Self {
         Self {
             inner: Mutex::new(EventLoopCore {
                 slots: ArrayVec::new(),
                 next_slot: 0,
+                tick_source,
+                #[cfg(AVR_WDT)]
+                supervisor: None,
             }),
         }
     }
@@ -185,6 +239,17 @@ impl EventLoop {
     fn configure(&self) {
         let mut core = self.inner.lock();
         core.configure_timer();
+        #[cfg(AVR_WDT)]
+        core.start_supervisor();
+    }
+
+    /// Install a watchdog supervisor.  The watchdog is armed when
+    /// run() is called and is only reset after a turn of the loop
+    /// if all of the tasks registered with the supervisor have
+    /// checked in within their deadlines.
+    #[cfg(AVR_WDT)]
+    pub fn set_supervisor(&self, supervisor: Supervisor) {
+        self.inner.lock().supervisor = Some(supervisor);
     }
 
     pub fn run(&self) -> ! {
@@ -198,15 +263,25 @@ impl EventLoop {
         logln!("starting run");
 
         let mut last_tick = unsafe { read_volatile(&TICKS) };
+        let mut last_frequency = clock::frequency();
         loop {
             let now_tick = unsafe { read_volatile(&TICKS) };
             let elapsed_ticks = now_tick - last_tick;
             last_tick = now_tick;
 
+            // Keep the tick rate steady if the clock prescaler was changed
+            let frequency = clock::frequency();
+            if frequency != last_frequency {
+                last_frequency = frequency;
+                self.inner.lock().configure_timer();
+            }
+
             self.turn(now_tick, elapsed_ticks);
+            #[cfg(AVR_WDT)]
+            self.inner.lock().supervise(elapsed_ticks);
 
             logln!("sleep");
-            sleep::wait_for_event(sleep::SleepMode::Idle);
+            sleep::wait_for_event();
         }
     }
 
@@ -306,13 +381,19 @@ impl EventLoop {
         logln!("made it down here");
         Ok(())
     }
+
+    /// Play a sequence of notes on `tone` without blocking the loop.
+    /// The tone is stopped when the melody completes.
+    #[cfg(all(AVR_TC3, AVR_PORTC))]
+    pub fn play(&self, tone: Tone, notes: &'static [Note]) -> Result<(), ()> {
+        self.spawn(Melody::new(tone, notes))
+    }
 }
 
-fn timer1_compare_a() {
+/// Called from the interrupt handler of the TickSource
+fn tick() {
     unsafe {
         write_volatile(&mut TICKS.ticks, read_volatile(&TICKS.ticks) + 1);
         sleep::set_event_pending();
     }
 }
-
-irq_handler!(TIMER1_COMPA, timer1_compare_a);
@@ -0,0 +1,331 @@
+//! External interrupts on the INTn pins and pin change interrupts
+//! on the PCINT pins.
+//!
+//! On the atmega32u4 INT0-INT3 are on PD0-PD3, INT6 is on PE6 and
+//! PCINT0-PCINT7 are on PB0-PB7.  INT0-INT3 and the pin change
+//! interrupts are detected asynchronously and can wake the MCU from
+//! `SleepMode::PowerDown` on any sense; INT6 can only do so when
+//! configured for `Sense::Low`.
+//!
+//! Each interrupt can be routed to a handler function, and the event
+//! loop can consume them as a Stream:
+//!
+//! ```
+//! exti::configure(ExtInt::Int0, Sense::Falling);
+//! exti::enable(ExtInt::Int0);
+//! events.spawn_stream(exti::stream(ExtInt::Int0).map(|_count| scan_matrix()))?;
+//! ```
+use mcu::{ExintEicraFlags, ExintEicrbFlags, ExintEifrFlags, ExintEimskFlags, EXINT};
+use mutex::{interrupt_free, CriticalSection};
+use core::ptr::{read_volatile, write_volatile};
+use futures::{Async, Poll, Stream};
+use sleep;
+
+/// Selects one of the INTn external interrupts
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExtInt {
+    #[cfg(AVR_IRQ_INT0)]
+    Int0,
+    #[cfg(AVR_IRQ_INT1)]
+    Int1,
+    #[cfg(AVR_IRQ_INT2)]
+    Int2,
+    #[cfg(AVR_IRQ_INT3)]
+    Int3,
+    #[cfg(AVR_IRQ_INT6)]
+    Int6,
+}
+
+impl ExtInt {
+    /// The INTn number, which is also the bit position in EIMSK and EIFR
+    #[inline]
+    fn number(&self) -> u8 {
+        match *self {
+            #[cfg(AVR_IRQ_INT0)]
+            ExtInt::Int0 => 0,
+            #[cfg(AVR_IRQ_INT1)]
+            ExtInt::Int1 => 1,
+            #[cfg(AVR_IRQ_INT2)]
+            ExtInt::Int2 => 2,
+            #[cfg(AVR_IRQ_INT3)]
+            ExtInt::Int3 => 3,
+            #[cfg(AVR_IRQ_INT6)]
+            ExtInt::Int6 => 6,
+        }
+    }
+
+    #[inline]
+    fn index(&self) -> usize {
+        self.number() as usize
+    }
+}
+
+/// Which condition on the pin triggers the interrupt
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Sense {
+    /// Triggers continuously while the pin is held low
+    Low = 0,
+    AnyEdge = 1,
+    Falling = 2,
+    Rising = 3,
+}
+
+/// The INTn vectors are numbered 0-7; INT4, INT5 and INT7 are
+/// not present on all parts
+const NUM_EXT_INTERRUPTS: usize = 8;
+
+/// The functions to call from the INTn vectors
+static mut HANDLERS: [Option<fn()>; NUM_EXT_INTERRUPTS] = [None; NUM_EXT_INTERRUPTS];
+/// The number of times each INTn has fired since the stream last polled
+static mut PENDING: [u8; NUM_EXT_INTERRUPTS] = [0; NUM_EXT_INTERRUPTS];
+
+/// Set the sense control for an interrupt.  The interrupt is disabled
+/// while the sense is changed, as that can trigger a spurious interrupt,
+/// and its flag is cleared afterwards.  The prior enable state is restored.
+pub fn configure(int: ExtInt, sense: Sense) {
+    let _cs = CriticalSection::new();
+    let n = int.number();
+    let mask = ExintEimskFlags::from_bits(1 << n);
+    unsafe {
+        let exint = &(*EXINT.get());
+        let enabled = exint.eimsk.read() & mask;
+        exint.eimsk.modify(|x| x - mask);
+
+        if n < 4 {
+            let shift = 2 * n;
+            exint.eicra.modify(|x| {
+                (x - ExintEicraFlags::from_bits(0b11 << shift))
+                    | ExintEicraFlags::from_bits((sense as u8) << shift)
+            });
+        } else {
+            let shift = 2 * (n - 4);
+            exint.eicrb.modify(|x| {
+                (x - ExintEicrbFlags::from_bits(0b11 << shift))
+                    | ExintEicrbFlags::from_bits((sense as u8) << shift)
+            });
+        }
+
+        // Flags are cleared by writing a one
+        exint.eifr.write(ExintEifrFlags::from_bits(1 << n));
+        exint.eimsk.modify(|x| x | enabled);
+    }
+}
+
+pub fn enable(int: ExtInt) {
+    let _cs = CriticalSection::new();
+    unsafe {
+        (*EXINT.get())
+            .eimsk
+            .modify(|x| x | ExintEimskFlags::from_bits(1 << int.number()));
+    }
+}
+
+pub fn disable(int: ExtInt) {
+    let _cs = CriticalSection::new();
+    unsafe {
+        (*EXINT.get())
+            .eimsk
+            .modify(|x| x - ExintEimskFlags::from_bits(1 << int.number()));
+    }
+}
+
+/// Register a function to be called from the interrupt vector.
+pub fn set_interrupt_handler(int: ExtInt, handler: Option<fn()>) {
+    interrupt_free(|_cs| unsafe {
+        write_volatile(&mut HANDLERS[int.index()], handler);
+    });
+}
+
+#[inline]
+fn dispatch(index: usize) {
+    unsafe {
+        let pending = read_volatile(&PENDING[index]);
+        write_volatile(&mut PENDING[index], pending.saturating_add(1));
+        sleep::set_event_pending();
+        if let Some(handler) = read_volatile(&HANDLERS[index]) {
+            handler();
+        }
+    }
+}
+
+#[cfg(AVR_IRQ_INT0)]
+fn int0() {
+    dispatch(0);
+}
+#[cfg(AVR_IRQ_INT0)]
+irq_handler!(INT0, int0);
+
+#[cfg(AVR_IRQ_INT1)]
+fn int1() {
+    dispatch(1);
+}
+#[cfg(AVR_IRQ_INT1)]
+irq_handler!(INT1, int1);
+
+#[cfg(AVR_IRQ_INT2)]
+fn int2() {
+    dispatch(2);
+}
+#[cfg(AVR_IRQ_INT2)]
+irq_handler!(INT2, int2);
+
+#[cfg(AVR_IRQ_INT3)]
+fn int3() {
+    dispatch(3);
+}
+#[cfg(AVR_IRQ_INT3)]
+irq_handler!(INT3, int3);
+
+#[cfg(AVR_IRQ_INT6)]
+fn int6() {
+    dispatch(6);
+}
+#[cfg(AVR_IRQ_INT6)]
+irq_handler!(INT6, int6);
+
+/// A Stream that yields each time an external interrupt fires.
+/// The item is the number of times that it fired since the previous
+/// item was produced.  The stream never ends.
+pub struct ExtIntStream {
+    int: ExtInt,
+}
+
+/// Returns a Stream of the occurrences of an interrupt.  Occurrences
+/// prior to this call are discarded.
+pub fn stream(int: ExtInt) -> ExtIntStream {
+    interrupt_free(|_cs| unsafe {
+        write_volatile(&mut PENDING[int.index()], 0);
+    });
+    ExtIntStream { int }
+}
+
+impl Stream for ExtIntStream {
+    type Item = u8;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<u8>, ()> {
+        let _cs = CriticalSection::new();
+        let index = self.int.index();
+        let pending = unsafe { read_volatile(&PENDING[index]) };
+        if pending == 0 {
+            return Ok(Async::NotReady);
+        }
+        unsafe {
+            write_volatile(&mut PENDING[index], 0);
+        }
+        Ok(Async::Ready(Some(pending)))
+    }
+}
+
+/// Pin change interrupts for the PCINT0-PCINT7 pins
+#[cfg(AVR_IRQ_PCINT0)]
+pub mod pcint {
+    use mcu::{ExintPcicrFlags, ExintPcifrFlags, ExintPcmsk0Flags, EXINT, PORTB};
+    use mutex::{interrupt_free, CriticalSection};
+    use core::ptr::{read_volatile, write_volatile};
+    use futures::{Async, Poll, Stream};
+    use sleep;
+
+    static mut HANDLER: Option<fn()> = None;
+    /// The PINB value sampled by the most recent pin change interrupt
+    static mut LAST_PINS: Option<u8> = None;
+
+    const PCIE0: ExintPcicrFlags = ExintPcicrFlags::from_bits(1 << 0);
+    const PCIF0: ExintPcifrFlags = ExintPcifrFlags::from_bits(1 << 0);
+
+    /// Include the PCINTn pin in the set that trigger the interrupt
+    pub fn enable_pin(n: u8) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*EXINT.get())
+                .pcmsk0
+                .modify(|x| x | ExintPcmsk0Flags::from_bits(1 << n));
+        }
+    }
+
+    pub fn disable_pin(n: u8) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*EXINT.get())
+                .pcmsk0
+                .modify(|x| x - ExintPcmsk0Flags::from_bits(1 << n));
+        }
+    }
+
+    /// Enable the pin change interrupt, discarding any change
+    /// that happened while it was disabled
+    pub fn enable() {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let exint = &(*EXINT.get());
+            exint.pcifr.write(PCIF0);
+            exint.pcicr.modify(|x| x | PCIE0);
+        }
+    }
+
+    pub fn disable() {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*EXINT.get()).pcicr.modify(|x| x - PCIE0);
+        }
+    }
+
+    /// Register a function to be called from the interrupt vector.
+    pub fn set_interrupt_handler(handler: Option<fn()>) {
+        interrupt_free(|_cs| unsafe {
+            write_volatile(&mut HANDLER, handler);
+        });
+    }
+
+    fn pcint0() {
+        unsafe {
+            write_volatile(&mut LAST_PINS, Some(read_pins()));
+            sleep::set_event_pending();
+            if let Some(handler) = read_volatile(&HANDLER) {
+                handler();
+            }
+        }
+    }
+
+    irq_handler!(PCINT0, pcint0);
+
+    #[inline]
+    fn read_pins() -> u8 {
+        // PCINT0-7 map to PB0-7
+        unsafe { (*PORTB.get()).pinb.read().bits() }
+    }
+
+    /// A Stream that yields the PINB value each time the pin change
+    /// interrupt fires.  If several changes happen between polls,
+    /// only the most recent state is produced.  The stream never ends.
+    pub struct PinChangeStream {
+        _private: (),
+    }
+
+    /// Returns a Stream of pin states.  Changes prior to this
+    /// call are discarded.
+    pub fn stream() -> PinChangeStream {
+        interrupt_free(|_cs| unsafe {
+            write_volatile(&mut LAST_PINS, None);
+        });
+        PinChangeStream { _private: () }
+    }
+
+    impl Stream for PinChangeStream {
+        type Item = u8;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Option<u8>, ()> {
+            let _cs = CriticalSection::new();
+            match unsafe { read_volatile(&LAST_PINS) } {
+                Some(pins) => {
+                    unsafe {
+                        write_volatile(&mut LAST_PINS, None);
+                    }
+                    Ok(Async::Ready(Some(pins)))
+                }
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use clock;
 
 // Suitable for adafruit 32u4 boards @ 8MHz
 #[cfg(feature = "clock_8mhz")]
@@ -7,22 +8,79 @@ pub const F_CPU: u32 = 8_000_000;
 #[cfg(feature = "clock_16mhz")]
 pub const F_CPU: u32 = 16_000_000;
 
-/// Busy wait for the specified number of ms
-// borrowed from https://github.com/shepmaster/rust-arduino-blink-led-no-core/blob/part3/hello.rs#L18
-pub fn busy_wait_ms(duration_ms: u16) {
-    const CYCLES_PER_MS: u16 = (F_CPU / 1000) as u16;
-    const CYCLES_PER_INNER_LOOP: u16 = 6; // From the disassembly
-    const INNER_LOOP_ITERATIONS: u16 = CYCLES_PER_MS / CYCLES_PER_INNER_LOOP;
-
-    let mut outer = 0;
-    while outer < duration_ms {
-        let mut inner = 0;
-        while inner < INNER_LOOP_ITERATIONS {
-            unsafe {
-                asm!("");
-            }
-            inner += 1;
+const CYCLES_PER_MS: u32 = F_CPU / 1000;
+const CYCLES_PER_US: u32 = F_CPU / 1_000_000;
+
+/// Spins for exactly 4 cycles per iteration (sbiw: 2, brne: 2) with the
+/// final iteration taking 3 cycles.  A count of 0 runs 65536 iterations.
+#[inline(always)]
+fn delay_loop_4(count: u16) {
+    let mut count = count;
+    unsafe {
+        asm!("1: sbiw $0, 1
+              brne 1b"
+             : "=w"(count)
+             : "0"(count)
+             :
+             : "volatile");
+    }
+}
+
+/// Busy wait for approximately the specified number of CPU cycles.
+/// When `cycles` is a constant the loop counts are computed at compile
+/// time and the delay is accurate to within a couple of cycles.
+#[inline(always)]
+pub fn delay_cycles(cycles: u32) {
+    let mut loops = cycles / 4;
+    while loops > 0xffff {
+        delay_loop_4(0);
+        loops -= 0x10000;
+    }
+    if loops > 0 {
+        delay_loop_4(loops as u16);
+    }
+    unsafe {
+        match cycles % 4 {
+            3 => asm!("NOP\nNOP\nNOP"::::"volatile"),
+            2 => asm!("NOP\nNOP"::::"volatile"),
+            1 => asm!("NOP"::::"volatile"),
+            _ => {}
         }
-        outer += 1;
+    }
+}
+
+/// Busy wait for the specified number of microseconds.
+/// The cycle count is scaled for the current clock::prescaler() at
+/// runtime, which costs a few tens of cycles on top of the requested
+/// delay.  Very short delays will run long, particularly when the
+/// clock has been slowed down.
+#[inline(always)]
+pub fn delay_us(duration_us: u16) {
+    delay_cycles((CYCLES_PER_US * duration_us as u32) >> clock::prescaler().shift());
+}
+
+/// Busy wait for the specified number of ms.
+/// Takes the current clock::prescaler() into account.
+pub fn busy_wait_ms(duration_ms: u16) {
+    let cycles = CYCLES_PER_MS >> clock::prescaler().shift();
+    for _ in 0..duration_ms {
+        delay_cycles(cycles);
+    }
+}
+
+/// Busy wait delays for code that wants a value to pass around.  With
+/// the `hal` feature enabled this also implements the
+/// embedded-hal `DelayMs` and `DelayUs` traits.
+pub struct Delay;
+
+impl Delay {
+    /// Busy wait for `ms` milliseconds; see busy_wait_ms()
+    pub fn delay_ms(&mut self, ms: u16) {
+        busy_wait_ms(ms);
+    }
+
+    /// Busy wait for `us` microseconds; see delay_us()
+    pub fn delay_us(&mut self, us: u16) {
+        delay_us(us);
     }
 }
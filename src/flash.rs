@@ -0,0 +1,234 @@
+//! Self-programming of the spare flash between the end of the
+//! application image and the bootloader, for storing data such as
+//! user keymaps that are too large for the EEPROM.
+//!
+//! The hardware only executes SPM from the boot loader section, so the
+//! routine that drives it is placed in the `.spm` link section, which
+//! must land inside the boot loader section as configured by the BOOTSZ
+//! fuses.  The stock Caterina and HalfKay bootloaders fill that section
+//! and refuse to program it, so this requires a custom bootloader build
+//! that leaves a page free at the top of its section to act as the SPM
+//! trampoline.  The application is then linked with, for example:
+//!
+//! ```text
+//! -Wl,--section-start=.spm=0x7f80
+//! ```
+//!
+//! and the combined image is flashed with an ISP programmer, as the
+//! bootloader will not write to its own section.
+//!
+//! `Flash::new()` reads the BOOTSZ fuses, checks that the routine ended
+//! up inside the boot loader section and returns `Error::Unsupported`
+//! rather than issuing SPM instructions that the hardware would ignore.
+//!
+//! Only whole pages within `spare_region()` can be erased or written;
+//! the application image and the bootloader are never touched.
+//!
+//! ```
+//! let mut flash = Flash::new()?;
+//! let addr = flash::spare_region().start;
+//! flash.write_page(addr, &page)?;
+//! ```
+use core::ops::Range;
+use mutex::CriticalSection;
+use progmem;
+use system::BOOTLOADER_ADDR;
+#[cfg(AVR_EEPROM)]
+use eeprom;
+
+/// The size of a flash page in bytes
+pub const PAGE_SIZE: usize = 128;
+
+/// I/O space address of SPMCSR.  SPM has to follow the write to SPMCSR
+/// within four cycles, so it is accessed directly from asm.
+const SPMCSR_IO: u8 = 0x37;
+
+const SPMEN: u8 = 1 << 0;
+const PGERS: u8 = 1 << 1;
+const PGWRT: u8 = 1 << 2;
+const BLBSET: u8 = 1 << 3;
+const RWWSRE: u8 = 1 << 4;
+
+/// The size of the atmega32u4's flash in bytes
+const FLASH_SIZE: u32 = 0x8000;
+
+/// The Z address that reads the high fuse byte after BLBSET
+const HIGH_FUSE_ADDR: u16 = 0x0003;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The address is not at the start of a page
+    Unaligned,
+    /// The page is not within the spare region
+    OutOfRange,
+    /// The SPM routine is not located in the boot loader section
+    Unsupported,
+}
+
+extern "C" {
+    /// Provided by the linker script; the end of the initial values
+    /// of `.data`, which is the last part of the application image.
+    static __data_load_end: u8;
+}
+
+/// The byte addresses of the whole pages that lie between the end of
+/// the application image and the bootloader
+pub fn spare_region() -> Range<usize> {
+    let end_of_image = unsafe { &__data_load_end as *const u8 as usize };
+    let start = (end_of_image + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let end = boot_section_start().min(BOOTLOADER_ADDR as u32);
+    start..end as usize
+}
+
+/// Read the high fuse byte.  LPM has to follow the write to SPMCSR
+/// within three cycles.
+fn read_high_fuse() -> u8 {
+    let _cs = CriticalSection::new();
+    let fuse: u8;
+    unsafe {
+        asm!("out $1, $2
+              lpm $0, Z"
+             : "=r"(fuse)
+             : "I"(SPMCSR_IO), "r"(BLBSET | SPMEN), "z"(HIGH_FUSE_ADDR)
+             :
+             : "volatile");
+    }
+    fuse
+}
+
+/// The byte address of the start of the boot loader section, as
+/// configured by the BOOTSZ fuses
+pub fn boot_section_start() -> u32 {
+    // BOOTSZ1:0 are bits 2:1; unprogrammed (1) bits select a smaller
+    // section, from 512 bytes for 0b11 up to 4KB for 0b00.
+    let bootsz = (read_high_fuse() >> 1) & 0b11;
+    FLASH_SIZE - (512 << (3 - bootsz))
+}
+
+/// Spin until SPMEN clears at the end of the previous SPM operation.
+/// This is written in asm so that nothing is called outside the boot
+/// loader section while the application section is busy.
+#[inline(always)]
+unsafe fn wait_spm() {
+    asm!("1: in r0, $0
+          sbrc r0, 0
+          rjmp 1b"
+         :
+         : "I"(SPMCSR_IO)
+         : "r0"
+         : "volatile");
+}
+
+#[inline(always)]
+unsafe fn spm(addr: u16, command: u8) {
+    asm!("out $0, $1
+          spm"
+         :
+         : "I"(SPMCSR_IO), "r"(command), "z"(addr)
+         :
+         : "volatile");
+}
+
+/// Load a word into the temporary page buffer.  SPM takes the
+/// data from r1:r0, so r1 is restored to zero afterwards.
+#[inline(always)]
+unsafe fn spm_fill(addr: u16, word: u16) {
+    asm!("movw r0, $2
+          out $0, $1
+          spm
+          clr r1"
+         :
+         : "I"(SPMCSR_IO), "r"(SPMEN), "r"(word), "z"(addr)
+         : "r0"
+         : "volatile");
+}
+
+/// Erase the page at `addr` and, if `data` is not null, program it
+/// with PAGE_SIZE bytes from `data`.  This must run entirely from the
+/// boot loader section with interrupts disabled, so everything it
+/// calls is inlined and there are no bounds checks.
+#[link_section = ".spm"]
+#[inline(never)]
+unsafe fn program_page(addr: u16, data: *const u8) {
+    wait_spm();
+    spm(addr, PGERS | SPMEN);
+    wait_spm();
+
+    if !data.is_null() {
+        let mut offset = 0;
+        while offset < PAGE_SIZE {
+            let lo = *data.offset(offset as isize) as u16;
+            let hi = *data.offset(offset as isize + 1) as u16;
+            spm_fill(addr + offset as u16, lo | (hi << 8));
+            offset += 2;
+        }
+        spm(addr, PGWRT | SPMEN);
+        wait_spm();
+    }
+
+    // Make the application section readable again
+    spm(addr, RWWSRE | SPMEN);
+    wait_spm();
+}
+
+pub struct Flash {
+    _private: (),
+}
+
+impl Flash {
+    pub fn new() -> Result<Self, Error> {
+        // Function pointers hold word addresses
+        let routine = program_page as usize as u32 * 2;
+        if routine < boot_section_start() || routine >= FLASH_SIZE {
+            return Err(Error::Unsupported);
+        }
+        Ok(Self { _private: () })
+    }
+
+    fn check_page(addr: usize) -> Result<(), Error> {
+        if addr % PAGE_SIZE != 0 {
+            return Err(Error::Unaligned);
+        }
+        let spare = spare_region();
+        if addr < spare.start || addr + PAGE_SIZE > spare.end {
+            return Err(Error::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn program(&mut self, addr: usize, data: *const u8) -> Result<(), Error> {
+        Self::check_page(addr)?;
+        // The interrupt vectors are unreadable while the page is being
+        // programmed, and SPM fails while an EEPROM write is in progress.
+        let _cs = CriticalSection::new();
+        #[cfg(AVR_EEPROM)]
+        while eeprom::is_busy() {}
+        unsafe {
+            program_page(addr as u16, data);
+        }
+        Ok(())
+    }
+
+    /// Erase the page at `addr`, leaving every byte as 0xff
+    pub fn erase_page(&mut self, addr: usize) -> Result<(), Error> {
+        self.program(addr, 0 as *const u8)
+    }
+
+    /// Erase the page at `addr` and program it with `data`.  This takes
+    /// around 8ms, during which interrupts are disabled.
+    pub fn write_page(&mut self, addr: usize, data: &[u8; PAGE_SIZE]) -> Result<(), Error> {
+        self.program(addr, data.as_ptr())
+    }
+
+    /// Read the bytes starting at `addr` within the spare region
+    pub fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let spare = spare_region();
+        if addr < spare.start || addr > spare.end || buf.len() > spare.end - addr {
+            return Err(Error::OutOfRange);
+        }
+        unsafe {
+            progmem::read_bytes(addr as *const u8, buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+}
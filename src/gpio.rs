@@ -0,0 +1,135 @@
+//! Typestate GPIO pins.  The mode of each pin is part of its type, so
+//! it is not possible to drive a pin that has not been configured as
+//! an output, or to read the pull up state of an output.
+//!
+//! The Port types and their pins are generated by build.rs from the
+//! PORT instances of the target MCU.
+//!
+//! ```
+//! let portc = Portc::take().unwrap();
+//! let mut led = portc.pc7.into_output();
+//! led.set_high();
+//! ```
+use core::marker::PhantomData;
+use mcu;
+use mutex::CriticalSection;
+
+/// Low level access to the DDRx, PORTx and PINx registers of a port.
+/// Implemented for each port by build.rs.
+pub trait Port {
+    fn set_ddr(mask: u8);
+    fn clear_ddr(mask: u8);
+    fn set_port(mask: u8);
+    fn clear_port(mask: u8);
+    fn toggle_port(mask: u8);
+    fn read_port(mask: u8) -> bool;
+    fn read_pin(mask: u8) -> bool;
+}
+
+/// Identifies a bit within a port
+pub trait PinNumber {
+    const MASK: u8;
+}
+
+macro_rules! pin_numbers {
+    ($($N:ident = $bit:expr),+) => {
+        $(
+            pub struct $N;
+            impl PinNumber for $N {
+                const MASK: u8 = 1 << $bit;
+            }
+        )+
+    };
+}
+
+pin_numbers!(N0 = 0, N1 = 1, N2 = 2, N3 = 3, N4 = 4, N5 = 5, N6 = 6, N7 = 7);
+
+/// Input mode; the type parameter selects the pull up state
+pub struct Input<PULL> {
+    _pull: PhantomData<PULL>,
+}
+
+/// The internal pull up resistor is disabled
+pub struct Floating;
+/// The internal pull up resistor is enabled
+pub struct PullUp;
+
+/// Push-pull output mode
+pub struct Output;
+
+pub struct Pin<PORT, N, MODE> {
+    _marker: PhantomData<(PORT, N, MODE)>,
+}
+
+impl<PORT: Port, N: PinNumber, MODE> Pin<PORT, N, MODE> {
+    #[inline]
+    fn new() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+
+    /// Configure the pin as an output.  The pin initially drives
+    /// the level that was last set; for an input that is low if it
+    /// was floating or high if the pull up was enabled.
+    pub fn into_output(self) -> Pin<PORT, N, Output> {
+        PORT::set_ddr(N::MASK);
+        Pin::new()
+    }
+
+    /// Configure the pin as an input with the pull up disabled
+    pub fn into_floating_input(self) -> Pin<PORT, N, Input<Floating>> {
+        PORT::clear_ddr(N::MASK);
+        PORT::clear_port(N::MASK);
+        Pin::new()
+    }
+
+    /// Configure the pin as an input with the pull up enabled
+    pub fn into_pull_up_input(self) -> Pin<PORT, N, Input<PullUp>> {
+        PORT::clear_ddr(N::MASK);
+        PORT::set_port(N::MASK);
+        Pin::new()
+    }
+}
+
+impl<PORT: Port, N: PinNumber> Pin<PORT, N, Output> {
+    #[inline]
+    pub fn set_high(&mut self) {
+        PORT::set_port(N::MASK);
+    }
+
+    #[inline]
+    pub fn set_low(&mut self) {
+        PORT::clear_port(N::MASK);
+    }
+
+    #[inline]
+    pub fn toggle(&mut self) {
+        PORT::toggle_port(N::MASK);
+    }
+
+    /// Returns true if the pin is being driven high
+    #[inline]
+    pub fn is_set_high(&self) -> bool {
+        PORT::read_port(N::MASK)
+    }
+
+    #[inline]
+    pub fn is_set_low(&self) -> bool {
+        !self.is_set_high()
+    }
+}
+
+impl<PORT: Port, N: PinNumber, PULL> Pin<PORT, N, Input<PULL>> {
+    #[inline]
+    pub fn is_high(&self) -> bool {
+        PORT::read_pin(N::MASK)
+    }
+
+    #[inline]
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/gpio.rs"));
@@ -0,0 +1,207 @@
+//! Implementations of the embedded-hal traits, so that drivers written
+//! against embedded-hal can be used with the peripherals in this crate.
+//! Enabled by the `hal` feature.
+use embedded_hal::blocking::delay;
+use embedded_hal::digital::{InputPin, OutputPin, StatefulOutputPin, ToggleableOutputPin};
+use embedded_hal::timer::{CountDown, Periodic};
+use nb;
+use void::Void;
+use clock;
+use fcpu::Delay;
+use gpio::{Input, Output, Pin, PinNumber, Port};
+use timer1::{self, ClockSource, Interrupt, WaveformGenerationMode};
+#[cfg(AVR_SPI)]
+use embedded_hal::blocking::spi as blocking_spi;
+#[cfg(AVR_SPI)]
+use spi::Spi;
+
+impl<PORT: Port, N: PinNumber> OutputPin for Pin<PORT, N, Output> {
+    fn set_low(&mut self) {
+        Pin::set_low(self)
+    }
+
+    fn set_high(&mut self) {
+        Pin::set_high(self)
+    }
+}
+
+impl<PORT: Port, N: PinNumber> StatefulOutputPin for Pin<PORT, N, Output> {
+    fn is_set_high(&self) -> bool {
+        Pin::is_set_high(self)
+    }
+
+    fn is_set_low(&self) -> bool {
+        Pin::is_set_low(self)
+    }
+}
+
+impl<PORT: Port, N: PinNumber> ToggleableOutputPin for Pin<PORT, N, Output> {
+    fn toggle(&mut self) {
+        Pin::toggle(self)
+    }
+}
+
+impl<PORT: Port, N: PinNumber, PULL> InputPin for Pin<PORT, N, Input<PULL>> {
+    fn is_high(&self) -> bool {
+        Pin::is_high(self)
+    }
+
+    fn is_low(&self) -> bool {
+        Pin::is_low(self)
+    }
+}
+
+impl delay::DelayMs<u8> for Delay {
+    fn delay_ms(&mut self, ms: u8) {
+        Delay::delay_ms(self, ms as u16);
+    }
+}
+
+impl delay::DelayMs<u16> for Delay {
+    fn delay_ms(&mut self, ms: u16) {
+        Delay::delay_ms(self, ms);
+    }
+}
+
+impl delay::DelayMs<u32> for Delay {
+    fn delay_ms(&mut self, ms: u32) {
+        let mut remaining = ms;
+        while remaining > 0xffff {
+            Delay::delay_ms(self, 0xffff);
+            remaining -= 0xffff;
+        }
+        Delay::delay_ms(self, remaining as u16);
+    }
+}
+
+impl delay::DelayUs<u8> for Delay {
+    fn delay_us(&mut self, us: u8) {
+        Delay::delay_us(self, us as u16);
+    }
+}
+
+impl delay::DelayUs<u16> for Delay {
+    fn delay_us(&mut self, us: u16) {
+        Delay::delay_us(self, us);
+    }
+}
+
+impl delay::DelayUs<u32> for Delay {
+    fn delay_us(&mut self, us: u32) {
+        let mut remaining = us;
+        while remaining > 0xffff {
+            Delay::delay_us(self, 0xffff);
+            remaining -= 0xffff;
+        }
+        Delay::delay_us(self, remaining as u16);
+    }
+}
+
+/// A frequency in Hz; the unit of time for Timer1CountDown
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Hertz(pub u32);
+
+impl From<u32> for Hertz {
+    fn from(hz: u32) -> Self {
+        Hertz(hz)
+    }
+}
+
+const PRESCALERS: [ClockSource; 5] = [
+    ClockSource::Prescale1,
+    ClockSource::Prescale8,
+    ClockSource::Prescale64,
+    ClockSource::Prescale256,
+    ClockSource::Prescale1024,
+];
+
+/// A periodic CountDown using Timer1 in CTC mode.  The compare match
+/// flag is polled, so no interrupt handler is required, but that means
+/// that Timer1 cannot also be used as the TickSource for the event loop.
+pub struct Timer1CountDown {
+    timer: Option<timer1::Handle>,
+}
+
+impl Timer1CountDown {
+    /// Returns None if something else already owns Timer1
+    pub fn new() -> Option<Self> {
+        if !timer1::claim() {
+            return None;
+        }
+        Some(Self { timer: None })
+    }
+
+    /// Stop the timer and release it
+    pub fn cancel(&mut self) {
+        self.timer.take();
+    }
+}
+
+impl CountDown for Timer1CountDown {
+    type Time = Hertz;
+
+    /// Start counting down at the specified rate.  Rates that are
+    /// out of range for the timer are clamped to the slowest or
+    /// fastest possible rate for the current CPU frequency.
+    fn start<T: Into<Hertz>>(&mut self, rate: T) {
+        let Hertz(hz) = rate.into();
+        let cpu = clock::frequency();
+        let hz = if hz == 0 { 1 } else { hz };
+
+        let mut settings = (ClockSource::Prescale1024, 0xffff);
+        for source in PRESCALERS.iter() {
+            let count = source.divisor().map(|div| cpu / (div * hz)).unwrap_or(0);
+            if count <= 0x1_0000 {
+                settings = (*source, count.saturating_sub(1) as u16);
+                break;
+            }
+        }
+
+        // Release the timer first so that the new configuration starts
+        // from a clean state
+        self.timer.take();
+        let timer = timer1::Timer::new()
+            .waveform_generation_mode(WaveformGenerationMode::ClearOnTimerMatchOutputCompare)
+            .clock_source(settings.0)
+            .output_compare(timer1::Channel::A, settings.1)
+            .configure();
+        timer.take_interrupt_flag(Interrupt::CompareA);
+        self.timer = Some(timer);
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        match self.timer {
+            Some(ref timer) if timer.take_interrupt_flag(Interrupt::CompareA) => Ok(()),
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+impl Periodic for Timer1CountDown {}
+
+impl Drop for Timer1CountDown {
+    fn drop(&mut self) {
+        self.timer.take();
+        timer1::release();
+    }
+}
+
+#[cfg(AVR_SPI)]
+impl blocking_spi::Transfer<u8> for Spi {
+    type Error = Void;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Void> {
+        Spi::transfer(self, words);
+        Ok(words)
+    }
+}
+
+#[cfg(AVR_SPI)]
+impl blocking_spi::Write<u8> for Spi {
+    type Error = Void;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Void> {
+        Spi::write(self, words);
+        Ok(())
+    }
+}
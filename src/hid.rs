@@ -0,0 +1,649 @@
+//! USB HID keyboard.
+//!
+//! The keyboard has two interfaces.  The first implements the boot
+//! protocol, so that it also works in a BIOS or bootloader that doesn't
+//! parse report descriptors, and sends 8 byte reports with up to six
+//! keys from interrupt IN endpoint 1.  The second sends a bitmap of all
+//! of the keys (N-key rollover) from endpoint 2.  NKRO reports are used
+//! while NKRO is enabled and the host has selected the report protocol;
+//! otherwise the keyboard falls back to the boot interface.
+//!
+//! The lock key LEDs set by the host are delivered as a Stream:
+//!
+//! ```
+//! let keyboard = Keyboard::new().unwrap();
+//! events.spawn_stream(keyboard.led_changes().for_each(|leds| { ... }))?;
+//!
+//! let mut report = KeyboardReport::new();
+//! report.press(0x04); // a
+//! events.spawn(keyboard.send(report).map(|keyboard| { ... }))?;
+//! ```
+use usb::{self, Class, Control, Direction, EndpointConfig, EndpointType, InEndpoint,
+          SetupPacket, Usb};
+use mutex::Mutex;
+use progmem::ProgSlice;
+use futures::{Async, Future, Poll, Stream};
+
+/// The IN endpoint used for boot keyboard reports
+const KEYBOARD_ENDPOINT: u8 = 1;
+const KEYBOARD_INTERFACE: u16 = 0;
+/// The IN endpoint used for NKRO reports
+const NKRO_ENDPOINT: u8 = 2;
+const NKRO_INTERFACE: u16 = 1;
+
+const REPORT_SIZE: usize = 8;
+
+/// The NKRO bitmap covers the key codes below the modifiers
+const NUM_KEYS: usize = 0xe0;
+const BITMAP_SIZE: usize = NUM_KEYS / 8;
+/// A modifier byte followed by the bitmap
+const NKRO_REPORT_SIZE: usize = 1 + BITMAP_SIZE;
+const NKRO_ENDPOINT_SIZE: u16 = 32;
+
+// Descriptor types
+const DEVICE: u8 = 1;
+const CONFIGURATION: u8 = 2;
+const STRING: u8 = 3;
+const HID: u8 = 0x21;
+const REPORT: u8 = 0x22;
+
+// HID class requests
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+const SET_PROTOCOL: u8 = 0x0b;
+
+/// The offsets of the HID descriptors within the configuration descriptor
+const HID_DESCRIPTOR_OFFSET: usize = 18;
+const NKRO_HID_DESCRIPTOR_OFFSET: usize = 43;
+const HID_DESCRIPTOR_SIZE: usize = 9;
+
+progmem! {
+    static DEVICE_DESCRIPTOR: [u8; 18] = [
+        18, DEVICE,
+        0x00, 0x02, // USB 2.0
+        0, 0, 0, // class is specified by the interface
+        usb::ENDPOINT0_SIZE as u8,
+        0xc0, 0x16, // vendor id
+        0x7c, 0x04, // product id
+        0x00, 0x01, // device version
+        1, 2, 0, // manufacturer, product and serial number strings
+        1, // number of configurations
+    ];
+
+    static CONFIGURATION_DESCRIPTOR: [u8; 59] = [
+        9, CONFIGURATION,
+        59, 0, // total length
+        2, // number of interfaces
+        1, // configuration value
+        0,
+        0xa0, // bus powered, supports remote wakeup
+        50, // 100mA
+
+        // Interface
+        9, 4,
+        KEYBOARD_INTERFACE as u8,
+        0, // alternate setting
+        1, // number of endpoints
+        0x03, 0x01, 0x01, // HID, boot interface, keyboard
+        0,
+
+        // HID
+        9, HID,
+        0x11, 0x01, // HID 1.11
+        0, // country code
+        1, // number of class descriptors
+        REPORT, 64, 0,
+
+        // Endpoint
+        7, 5,
+        0x80 | KEYBOARD_ENDPOINT,
+        0x03, // interrupt
+        REPORT_SIZE as u8, 0,
+        1, // poll every 1ms
+
+        // NKRO interface
+        9, 4,
+        NKRO_INTERFACE as u8,
+        0, // alternate setting
+        1, // number of endpoints
+        0x03, 0x00, 0x00, // HID, no boot protocol
+        0,
+
+        // HID
+        9, HID,
+        0x11, 0x01, // HID 1.11
+        0, // country code
+        1, // number of class descriptors
+        REPORT, 33, 0,
+
+        // Endpoint
+        7, 5,
+        0x80 | NKRO_ENDPOINT,
+        0x03, // interrupt
+        NKRO_ENDPOINT_SIZE as u8, 0,
+        1, // poll every 1ms
+    ];
+
+    /// The standard boot keyboard report layout: a modifier byte, a
+    /// reserved byte and six key codes, with five LEDs as output
+    static REPORT_DESCRIPTOR: [u8; 64] = [
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x06, // Usage (Keyboard)
+        0xa1, 0x01, // Collection (Application)
+        0x75, 0x01, //   Report Size (1)
+        0x95, 0x08, //   Report Count (8)
+        0x05, 0x07, //   Usage Page (Key Codes)
+        0x19, 0xe0, //   Usage Minimum (224)
+        0x29, 0xe7, //   Usage Maximum (231)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x25, 0x01, //   Logical Maximum (1)
+        0x81, 0x02, //   Input (Data, Variable, Absolute) ; Modifiers
+        0x95, 0x01, //   Report Count (1)
+        0x75, 0x08, //   Report Size (8)
+        0x81, 0x03, //   Input (Constant) ; Reserved
+        0x95, 0x05, //   Report Count (5)
+        0x75, 0x01, //   Report Size (1)
+        0x05, 0x08, //   Usage Page (LEDs)
+        0x19, 0x01, //   Usage Minimum (1)
+        0x29, 0x05, //   Usage Maximum (5)
+        0x91, 0x02, //   Output (Data, Variable, Absolute) ; LEDs
+        0x95, 0x01, //   Report Count (1)
+        0x75, 0x03, //   Report Size (3)
+        0x91, 0x03, //   Output (Constant) ; Padding
+        0x95, 0x06, //   Report Count (6)
+        0x75, 0x08, //   Report Size (8)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x26, 0xdf, 0x00, //   Logical Maximum (223)
+        0x05, 0x07, //   Usage Page (Key Codes)
+        0x19, 0x00, //   Usage Minimum (0)
+        0x29, 0xdf, //   Usage Maximum (223)
+        0x81, 0x00, //   Input (Data, Array) ; Keys
+        0xc0, // End Collection
+    ];
+
+    /// A modifier byte followed by one bit for each of the key codes
+    /// 0x00-0xdf
+    static NKRO_REPORT_DESCRIPTOR: [u8; 33] = [
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x06, // Usage (Keyboard)
+        0xa1, 0x01, // Collection (Application)
+        0x75, 0x01, //   Report Size (1)
+        0x95, 0x08, //   Report Count (8)
+        0x05, 0x07, //   Usage Page (Key Codes)
+        0x19, 0xe0, //   Usage Minimum (224)
+        0x29, 0xe7, //   Usage Maximum (231)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x25, 0x01, //   Logical Maximum (1)
+        0x81, 0x02, //   Input (Data, Variable, Absolute) ; Modifiers
+        0x95, 0xe0, //   Report Count (224)
+        0x75, 0x01, //   Report Size (1)
+        0x19, 0x00, //   Usage Minimum (0)
+        0x29, 0xdf, //   Usage Maximum (223)
+        0x81, 0x02, //   Input (Data, Variable, Absolute) ; Keys
+        0xc0, // End Collection
+    ];
+
+    static LANGUAGES: [u8; 4] = [4, STRING, 0x09, 0x04]; // US English
+
+    static MANUFACTURER: [u8; 20] = [
+        20, STRING,
+        b'f', 0, b'l', 0, b'u', 0, b't', 0, b't', 0, b'e', 0, b'r', 0, b'b', 0, b'y', 0,
+    ];
+
+    static PRODUCT: [u8; 38] = [
+        38, STRING,
+        b'f', 0, b'l', 0, b'u', 0, b't', 0, b't', 0, b'e', 0, b'r', 0, b'b', 0, b'y', 0,
+        b' ', 0, b'k', 0, b'e', 0, b'y', 0, b'b', 0, b'o', 0, b'a', 0, b'r', 0, b'd', 0,
+    ];
+}
+
+/// Modifier bits for `KeyboardReport::modifiers`
+pub const MOD_LEFT_CTRL: u8 = 1 << 0;
+pub const MOD_LEFT_SHIFT: u8 = 1 << 1;
+pub const MOD_LEFT_ALT: u8 = 1 << 2;
+pub const MOD_LEFT_GUI: u8 = 1 << 3;
+pub const MOD_RIGHT_CTRL: u8 = 1 << 4;
+pub const MOD_RIGHT_SHIFT: u8 = 1 << 5;
+pub const MOD_RIGHT_ALT: u8 = 1 << 6;
+pub const MOD_RIGHT_GUI: u8 = 1 << 7;
+
+/// Reported in every key slot of a boot report when more than
+/// six keys are pressed
+pub const KEY_ERROR_ROLLOVER: u8 = 0x01;
+
+/// The set of keys that are pressed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyboardReport {
+    pub modifiers: u8,
+    keys: [u8; BITMAP_SIZE],
+}
+
+impl KeyboardReport {
+    /// A report with no keys pressed
+    pub fn new() -> Self {
+        Self {
+            modifiers: 0,
+            keys: [0; BITMAP_SIZE],
+        }
+    }
+
+    /// Add a key code to the report.  The modifier key codes
+    /// 0xe0-0xe7 set the corresponding modifier bit.
+    pub fn press(&mut self, key: u8) {
+        if key >= 0xe0 && key <= 0xe7 {
+            self.modifiers |= 1 << (key - 0xe0);
+        } else if (key as usize) < NUM_KEYS {
+            self.keys[key as usize / 8] |= 1 << (key % 8);
+        }
+    }
+
+    pub fn release(&mut self, key: u8) {
+        if key >= 0xe0 && key <= 0xe7 {
+            self.modifiers &= !(1 << (key - 0xe0));
+        } else if (key as usize) < NUM_KEYS {
+            self.keys[key as usize / 8] &= !(1 << (key % 8));
+        }
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        if key >= 0xe0 && key <= 0xe7 {
+            self.modifiers & (1 << (key - 0xe0)) != 0
+        } else if (key as usize) < NUM_KEYS {
+            self.keys[key as usize / 8] & (1 << (key % 8)) != 0
+        } else {
+            false
+        }
+    }
+
+    /// The boot protocol layout: a modifier byte, a reserved byte and
+    /// six key codes.  If more than six keys are pressed the report
+    /// signals a rollover error.
+    fn boot_report(&self) -> [u8; REPORT_SIZE] {
+        let mut report = [0u8; REPORT_SIZE];
+        report[0] = self.modifiers;
+        let mut slot = 2;
+        // Key codes 0-3 are reserved for error conditions
+        for key in 4..NUM_KEYS as u8 {
+            if self.is_pressed(key) {
+                if slot == REPORT_SIZE {
+                    for slot in report[2..].iter_mut() {
+                        *slot = KEY_ERROR_ROLLOVER;
+                    }
+                    break;
+                }
+                report[slot] = key;
+                slot += 1;
+            }
+        }
+        report
+    }
+
+    fn nkro_report(&self) -> [u8; NKRO_REPORT_SIZE] {
+        let mut report = [0u8; NKRO_REPORT_SIZE];
+        report[0] = self.modifiers;
+        report[1..].copy_from_slice(&self.keys);
+        report
+    }
+}
+
+/// The report protocol selected by the host
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Boot = 0,
+    Report = 1,
+}
+
+/// The keyboard LEDs, as set by the host
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Leds(pub u8);
+
+impl Leds {
+    pub fn num_lock(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    pub fn caps_lock(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    pub fn scroll_lock(&self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    pub fn compose(&self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+
+    pub fn kana(&self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+}
+
+struct State {
+    protocol: Protocol,
+    /// In units of 4ms; 0 means only report changes
+    idle_rate: u8,
+    leds: u8,
+    leds_changed: bool,
+    /// The most recently sent report
+    keys: KeyboardReport,
+    /// The frame in which the report was sent, for the idle rate
+    sent_frame: u16,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    protocol: Protocol::Report,
+    idle_rate: 125,
+    leds: 0,
+    leds_changed: false,
+    keys: KeyboardReport {
+        modifiers: 0,
+        keys: [0; BITMAP_SIZE],
+    },
+    sent_frame: 0,
+});
+
+/// The `usb::Class` for the keyboard
+pub struct KeyboardClass;
+
+impl Class for KeyboardClass {
+    const ENDPOINTS: &'static [EndpointConfig] = &[
+        EndpointConfig {
+            number: KEYBOARD_ENDPOINT,
+            kind: EndpointType::Interrupt,
+            direction: Direction::In,
+            size: REPORT_SIZE as u16,
+            double_bank: false,
+        },
+        EndpointConfig {
+            number: NKRO_ENDPOINT,
+            kind: EndpointType::Interrupt,
+            direction: Direction::In,
+            size: NKRO_ENDPOINT_SIZE,
+            double_bank: false,
+        },
+    ];
+
+    fn descriptor(kind: u8, index: u8, language: u16) -> Option<ProgSlice> {
+        let config = CONFIGURATION_DESCRIPTOR.as_prog_slice();
+        match (kind, index, language) {
+            (DEVICE, 0, _) => Some(DEVICE_DESCRIPTOR.as_prog_slice()),
+            (CONFIGURATION, 0, _) => Some(config),
+            (STRING, 0, _) => Some(LANGUAGES.as_prog_slice()),
+            (STRING, 1, _) => Some(MANUFACTURER.as_prog_slice()),
+            (STRING, 2, _) => Some(PRODUCT.as_prog_slice()),
+            (HID, 0, KEYBOARD_INTERFACE) => {
+                config.slice(HID_DESCRIPTOR_OFFSET, HID_DESCRIPTOR_SIZE)
+            }
+            (HID, 0, NKRO_INTERFACE) => {
+                config.slice(NKRO_HID_DESCRIPTOR_OFFSET, HID_DESCRIPTOR_SIZE)
+            }
+            (REPORT, 0, KEYBOARD_INTERFACE) => Some(REPORT_DESCRIPTOR.as_prog_slice()),
+            (REPORT, 0, NKRO_INTERFACE) => Some(NKRO_REPORT_DESCRIPTOR.as_prog_slice()),
+            _ => None,
+        }
+    }
+
+    fn control(setup: &SetupPacket, control: &mut Control) -> bool {
+        // Class requests addressed to one of the keyboard interfaces
+        if setup.kind() != 1 || setup.recipient() != 1 {
+            return false;
+        }
+        let interface = setup.index;
+        if interface != KEYBOARD_INTERFACE && interface != NKRO_INTERFACE {
+            return false;
+        }
+        let mut state = STATE.lock();
+        match setup.request {
+            GET_REPORT if interface == KEYBOARD_INTERFACE => {
+                control.reply(&state.keys.boot_report())
+            }
+            GET_REPORT => control.reply(&state.keys.nkro_report()),
+            GET_IDLE => control.reply(&[state.idle_rate]),
+            SET_IDLE => {
+                state.idle_rate = (setup.value >> 8) as u8;
+                control.accept();
+            }
+            // The LEDs and the protocol belong to the boot interface
+            SET_REPORT if interface == KEYBOARD_INTERFACE => {
+                let mut leds = [0u8; 1];
+                if control.receive(&mut leds) == 1 {
+                    state.leds = leds[0];
+                    state.leds_changed = true;
+                }
+            }
+            GET_PROTOCOL if interface == KEYBOARD_INTERFACE => {
+                control.reply(&[state.protocol as u8])
+            }
+            SET_PROTOCOL if interface == KEYBOARD_INTERFACE => {
+                state.protocol = if setup.value == 0 {
+                    Protocol::Boot
+                } else {
+                    Protocol::Report
+                };
+                control.accept();
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn set_configured(_configured: bool) {
+        // The protocol reverts to the report protocol on reset
+        let mut state = STATE.lock();
+        state.protocol = Protocol::Report;
+        state.idle_rate = 125;
+    }
+}
+
+pub struct Keyboard {
+    usb: Usb,
+    boot_endpoint: InEndpoint,
+    nkro_endpoint: InEndpoint,
+    nkro_enabled: bool,
+    /// True if the host was last sent the keys via the NKRO interface
+    nkro_active: bool,
+}
+
+impl Keyboard {
+    /// Attach to the bus as a keyboard, with NKRO enabled.  Returns
+    /// None if the USB controller is already in use.
+    pub fn new() -> Option<Self> {
+        let mut usb = Usb::new::<KeyboardClass>()?;
+        let boot_endpoint = usb.in_endpoint(KEYBOARD_ENDPOINT)
+            .expect("keyboard endpoint is configured");
+        let nkro_endpoint = usb.in_endpoint(NKRO_ENDPOINT)
+            .expect("nkro endpoint is configured");
+        Some(Self {
+            usb,
+            boot_endpoint,
+            nkro_endpoint,
+            nkro_enabled: true,
+            nkro_active: false,
+        })
+    }
+
+    /// Returns true once the host has configured the keyboard
+    pub fn is_configured(&self) -> bool {
+        self.usb.is_configured()
+    }
+
+    /// The protocol that the host has selected
+    pub fn protocol(&self) -> Protocol {
+        STATE.lock().protocol
+    }
+
+    /// Allow or prevent the use of NKRO reports.  Takes effect
+    /// from the next report that is sent.
+    pub fn set_nkro(&mut self, enabled: bool) {
+        self.nkro_enabled = enabled;
+    }
+
+    pub fn is_nkro_enabled(&self) -> bool {
+        self.nkro_enabled
+    }
+
+    /// Returns true if reports will be sent via the NKRO interface;
+    /// NKRO has to be enabled and the host must have selected
+    /// the report protocol.
+    pub fn uses_nkro(&self) -> bool {
+        self.nkro_enabled && self.protocol() == Protocol::Report
+    }
+
+    /// The current state of the LEDs
+    pub fn leds(&self) -> Leds {
+        Leds(STATE.lock().leds)
+    }
+
+    /// Returns a Stream that yields the LED state each time
+    /// the host changes it
+    pub fn led_changes(&self) -> LedChanges {
+        LedChanges { _private: () }
+    }
+
+    /// Returns true if the host's idle rate requires the last report
+    /// to be sent again even though nothing has changed
+    pub fn idle_expired(&self) -> bool {
+        let state = STATE.lock();
+        state.idle_rate != 0
+            && usb::frames_since(state.sent_frame) >= state.idle_rate as u16 * 4
+    }
+
+    /// The report that was most recently sent
+    pub fn last_report(&self) -> KeyboardReport {
+        STATE.lock().keys
+    }
+
+    /// Wake the host if it is suspended, for example because a key
+    /// was pressed
+    pub fn remote_wakeup(&mut self) -> bool {
+        self.usb.remote_wakeup()
+    }
+
+    /// Returns a Future that sends `report` to the host and then
+    /// resolves to the Keyboard.  When switching between the boot and
+    /// NKRO interfaces while in the report protocol, an empty report is
+    /// sent via the old interface first so that the host does not see
+    /// its keys as held down.
+    pub fn send(mut self, report: KeyboardReport) -> SendReport {
+        let nkro = self.uses_nkro();
+        let release = if nkro != self.nkro_active && self.protocol() == Protocol::Report {
+            Some(Pending::new(&KeyboardReport::new(), self.nkro_active))
+        } else {
+            None
+        };
+        self.nkro_active = nkro;
+        SendReport {
+            keyboard: Some(self),
+            release,
+            report: Some(Pending::new(&report, nkro)),
+            keys: report,
+        }
+    }
+}
+
+/// A report waiting to be sent
+struct Pending {
+    nkro: bool,
+    data: [u8; NKRO_REPORT_SIZE],
+    len: usize,
+}
+
+impl Pending {
+    fn new(report: &KeyboardReport, nkro: bool) -> Self {
+        if nkro {
+            Self {
+                nkro,
+                data: report.nkro_report(),
+                len: NKRO_REPORT_SIZE,
+            }
+        } else {
+            let mut data = [0u8; NKRO_REPORT_SIZE];
+            data[..REPORT_SIZE].copy_from_slice(&report.boot_report());
+            Self {
+                nkro,
+                data,
+                len: REPORT_SIZE,
+            }
+        }
+    }
+
+    fn poll_send(&self, keyboard: &mut Keyboard) -> Poll<(), usb::Error> {
+        let endpoint = if self.nkro {
+            &mut keyboard.nkro_endpoint
+        } else {
+            &mut keyboard.boot_endpoint
+        };
+        endpoint.poll_write(&self.data[..self.len])
+    }
+}
+
+/// Send a pending report, if there is one, and clear it once sent
+fn send_pending(pending: &mut Option<Pending>, keyboard: &mut Keyboard) -> Poll<(), usb::Error> {
+    let sent = match *pending {
+        Some(ref report) => report.poll_send(keyboard)?,
+        None => return Ok(Async::Ready(())),
+    };
+    if sent.is_ready() {
+        *pending = None;
+    }
+    Ok(sent)
+}
+
+pub struct SendReport {
+    keyboard: Option<Keyboard>,
+    release: Option<Pending>,
+    report: Option<Pending>,
+    keys: KeyboardReport,
+}
+
+impl Future for SendReport {
+    type Item = Keyboard;
+    type Error = (Keyboard, usb::Error);
+
+    fn poll(&mut self) -> Poll<Keyboard, (Keyboard, usb::Error)> {
+        let result = {
+            let keyboard = self.keyboard.as_mut().expect("polled after completion");
+            match send_pending(&mut self.release, keyboard) {
+                Ok(Async::Ready(())) => send_pending(&mut self.report, keyboard),
+                other => other,
+            }
+        };
+
+        match result {
+            Ok(Async::Ready(())) => {
+                {
+                    let mut state = STATE.lock();
+                    state.keys = self.keys;
+                    state.sent_frame = usb::frame_count();
+                }
+                Ok(Async::Ready(self.keyboard.take().unwrap()))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(error) => Err((self.keyboard.take().unwrap(), error)),
+        }
+    }
+}
+
+/// A Stream of changes to the keyboard LEDs
+pub struct LedChanges {
+    _private: (),
+}
+
+impl Stream for LedChanges {
+    type Item = Leds;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Leds>, ()> {
+        let mut state = STATE.lock();
+        if state.leds_changed {
+            state.leds_changed = false;
+            Ok(Async::Ready(Some(Leds(state.leds))))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
@@ -14,7 +14,14 @@ pub mod macros;
 extern crate arrayvec;
 extern crate bare_metal;
 extern crate futures;
+extern crate record_store;
 extern crate volatile_register;
+#[cfg(feature = "hal")]
+extern crate embedded_hal;
+#[cfg(feature = "hal")]
+extern crate nb;
+#[cfg(feature = "hal")]
+extern crate void;
 
 #[cfg(feature = "simavr")]
 #[macro_use]
@@ -23,13 +30,56 @@ pub mod simavr;
 #[macro_use]
 pub mod mcu;
 pub mod mutex;
+pub mod gpio;
+pub mod ringbuffer;
+#[macro_use]
+pub mod progmem;
 pub mod fcpu;
 pub mod eventloop;
+#[cfg(AVR_EXINT)]
+pub mod exti;
+#[cfg(AVR_TC0)]
+pub mod timer0;
 pub mod timer1;
+#[cfg(AVR_TC3)]
+pub mod timer3;
+#[cfg(AVR_TC4)]
+pub mod timer4;
+#[cfg(AVR_PLL)]
+pub mod pll;
+#[cfg(AVR_PORTB)]
+pub mod pwm;
+#[cfg(AVR_PORTD)]
+pub mod capture;
+#[cfg(all(AVR_TC3, AVR_PORTC))]
+pub mod tone;
 #[cfg(AVR_WDT)]
 pub mod wdt;
+#[cfg(AVR_WDT)]
+pub mod supervisor;
 pub mod sleep;
 pub mod heap;
+pub mod system;
+#[cfg(any(feature = "feather32u4", feature = "teensy2"))]
+pub mod flash;
+pub mod power;
+pub mod clock;
+#[cfg(AVR_USART1)]
+pub mod usart;
+#[cfg(all(AVR_SPI, AVR_PORTB))]
+pub mod spi;
+#[cfg(all(AVR_TWI, AVR_PORTD))]
+pub mod twi;
+#[cfg(AVR_ADC)]
+pub mod adc;
+#[cfg(AVR_EEPROM)]
+pub mod eeprom;
+#[cfg(all(AVR_USB_DEVICE, AVR_PLL))]
+pub mod usb;
+#[cfg(all(AVR_USB_DEVICE, AVR_PLL))]
+pub mod hid;
+#[cfg(feature = "hal")]
+pub mod hal;
 
 // The bootloader may leave some devices in a state that will cause
 // a fault as soon as we re-enable interrupts.  Turn those things off
@@ -45,6 +95,9 @@ pub fn reset_peripherals() {
                 .write(mcu::UsbDeviceUsbconFlags::empty());
         }
 
+        power::initialize();
+        clock::initialize();
+
         #[cfg(AVR_WDT)]
         wdt::initialize_disabled();
     });
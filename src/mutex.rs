@@ -58,7 +58,7 @@ impl<'a, T: ?Sized> !Send for MutexGuard<'a, T> {}
 unsafe impl<'a, T: ?Sized + Sync> Sync for MutexGuard<'a, T> {}
 
 impl<T> Mutex<T> {
-    pub fn new(t: T) -> Mutex<T> {
+    pub const fn new(t: T) -> Mutex<T> {
         Self {
             data: UnsafeCell::new(t),
         }
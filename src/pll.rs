@@ -0,0 +1,54 @@
+//! The PLL generates the 48MHz clock needed by the USB controller and can
+//! also be used to clock the high speed Timer4.  It is shared by those
+//! drivers and keeps running while at least one PllHandle is alive.
+use mcu::{PllPllcsrFlags, PllPllfrqFlags, PLL};
+use mutex::interrupt_free;
+use core::ptr::{read_volatile, write_volatile};
+
+const PLOCK: PllPllcsrFlags = PllPllcsrFlags::from_bits(1 << 0);
+const PLLE: PllPllcsrFlags = PllPllcsrFlags::from_bits(1 << 1);
+/// The PLL needs an 8MHz input, so divide a 16MHz clock by 2
+#[cfg(feature = "clock_16mhz")]
+const PINDIV: PllPllcsrFlags = PllPllcsrFlags::from_bits(1 << 4);
+#[cfg(not(feature = "clock_16mhz"))]
+const PINDIV: PllPllcsrFlags = PllPllcsrFlags::from_bits(0);
+
+/// PDIV3:0 = 0100 selects a 48MHz PLL output
+const PDIV_48MHZ: PllPllfrqFlags = PllPllfrqFlags::from_bits(0b0100);
+const PDIV_MASK: PllPllfrqFlags = PllPllfrqFlags::from_bits(0x0f);
+
+static mut REFCOUNT: u8 = 0;
+
+/// Keeps the PLL running until it is dropped
+#[must_use]
+pub struct PllHandle {
+    _private: (),
+}
+
+/// Start the PLL (if it is not already running), wait for it to lock
+/// and return a handle that keeps it running.
+pub fn acquire() -> PllHandle {
+    interrupt_free(|_cs| unsafe {
+        let count = read_volatile(&REFCOUNT);
+        if count == 0 {
+            let pll = &(*PLL.get());
+            pll.pllfrq.modify(|x| (x - PDIV_MASK) | PDIV_48MHZ);
+            pll.pllcsr.write(PINDIV | PLLE);
+            while (pll.pllcsr.read() & PLOCK) != PLOCK {}
+        }
+        write_volatile(&mut REFCOUNT, count + 1);
+    });
+    PllHandle { _private: () }
+}
+
+impl Drop for PllHandle {
+    fn drop(&mut self) {
+        interrupt_free(|_cs| unsafe {
+            let count = read_volatile(&REFCOUNT) - 1;
+            write_volatile(&mut REFCOUNT, count);
+            if count == 0 {
+                (*PLL.get()).pllcsr.write(PllPllcsrFlags::empty());
+            }
+        });
+    }
+}
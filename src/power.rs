@@ -0,0 +1,138 @@
+//! Peripheral clock gating via the power reduction registers.
+//! All of the gated peripherals are switched off at startup.  Drivers
+//! acquire a PowerHandle for the peripheral(s) that they use when they
+//! are constructed; the clock is enabled while at least one handle for
+//! that peripheral is alive and is disabled again when the last one is
+//! dropped.  The set of active peripherals also determines how deeply
+//! the CPU can sleep while waiting for an event.
+use mcu::{CpuPrr0Flags, CpuPrr1Flags, CPU};
+use mutex::interrupt_free;
+use core::ptr::{read_volatile, write_volatile};
+use sleep::SleepMode;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Peripheral {
+    Timer0,
+    Timer1,
+    Timer3,
+    Timer4,
+    Usart1,
+    Spi,
+    Twi,
+    Adc,
+    Usb,
+}
+
+const NUM_PERIPHERALS: usize = 9;
+
+impl Peripheral {
+    #[inline]
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    #[inline]
+    fn bits(&self) -> (CpuPrr0Flags, CpuPrr1Flags) {
+        use self::Peripheral::*;
+        match *self {
+            Timer0 => (CpuPrr0Flags::PRTIM0, CpuPrr1Flags::empty()),
+            Timer1 => (CpuPrr0Flags::PRTIM1, CpuPrr1Flags::empty()),
+            Timer3 => (CpuPrr0Flags::empty(), CpuPrr1Flags::PRTIM3),
+            Timer4 => (CpuPrr0Flags::empty(), CpuPrr1Flags::PRTIM4),
+            Usart1 => (CpuPrr0Flags::empty(), CpuPrr1Flags::PRUSART1),
+            Spi => (CpuPrr0Flags::PRSPI, CpuPrr1Flags::empty()),
+            Twi => (CpuPrr0Flags::PRTWI, CpuPrr1Flags::empty()),
+            Adc => (CpuPrr0Flags::PRADC, CpuPrr1Flags::empty()),
+            Usb => (CpuPrr0Flags::empty(), CpuPrr1Flags::PRUSB),
+        }
+    }
+}
+
+static mut REFCOUNTS: [u8; NUM_PERIPHERALS] = [0; NUM_PERIPHERALS];
+
+/// Called by the main startup code, so you won't generally need to call this.
+/// Gates the clocks of all of the peripherals managed by this module.
+pub fn initialize() {
+    interrupt_free(|_cs| unsafe {
+        let cpu = &(*CPU.get());
+        cpu.prr0.write(
+            CpuPrr0Flags::PRTIM0 | CpuPrr0Flags::PRTIM1 | CpuPrr0Flags::PRSPI
+                | CpuPrr0Flags::PRTWI | CpuPrr0Flags::PRADC,
+        );
+        cpu.prr1.write(
+            CpuPrr1Flags::PRTIM3 | CpuPrr1Flags::PRTIM4 | CpuPrr1Flags::PRUSART1
+                | CpuPrr1Flags::PRUSB,
+        );
+        write_volatile(&mut REFCOUNTS, [0; NUM_PERIPHERALS]);
+    });
+}
+
+/// Keeps the clock for a peripheral enabled until it is dropped
+#[must_use]
+pub struct PowerHandle {
+    peripheral: Peripheral,
+}
+
+/// Enable the clock for the peripheral (if it is not already enabled)
+/// and return a handle that keeps it enabled.  Panics if there are
+/// already 255 live handles for the peripheral.
+pub fn acquire(peripheral: Peripheral) -> PowerHandle {
+    interrupt_free(|_cs| unsafe {
+        let idx = peripheral.index();
+        let count = read_volatile(&REFCOUNTS[idx]);
+        let next = count.checked_add(1).expect("too many PowerHandles");
+        if count == 0 {
+            let cpu = &(*CPU.get());
+            let (prr0, prr1) = peripheral.bits();
+            cpu.prr0.modify(|x| x - prr0);
+            cpu.prr1.modify(|x| x - prr1);
+        }
+        write_volatile(&mut REFCOUNTS[idx], next);
+    });
+    PowerHandle { peripheral }
+}
+
+impl PowerHandle {
+    pub fn peripheral(&self) -> Peripheral {
+        self.peripheral
+    }
+}
+
+impl Drop for PowerHandle {
+    fn drop(&mut self) {
+        let peripheral = self.peripheral;
+        interrupt_free(|_cs| unsafe {
+            let idx = peripheral.index();
+            let count = read_volatile(&REFCOUNTS[idx]) - 1;
+            write_volatile(&mut REFCOUNTS[idx], count);
+            if count == 0 {
+                let cpu = &(*CPU.get());
+                let (prr0, prr1) = peripheral.bits();
+                cpu.prr0.modify(|x| x | prr0);
+                cpu.prr1.modify(|x| x | prr1);
+            }
+        });
+    }
+}
+
+/// Returns true if there is at least one live PowerHandle
+/// for the peripheral.
+pub fn is_active(peripheral: Peripheral) -> bool {
+    unsafe { read_volatile(&REFCOUNTS[peripheral.index()]) != 0 }
+}
+
+/// Returns the deepest sleep mode that won't stop the clock
+/// of any of the active peripherals.
+pub fn deepest_sleep_mode() -> SleepMode {
+    use self::Peripheral::*;
+    // The ADC is the only one of these that keeps working
+    // without the I/O clock.
+    let needs_io_clock = [Timer0, Timer1, Timer3, Timer4, Usart1, Spi, Twi, Usb];
+    if needs_io_clock.iter().any(|p| is_active(*p)) {
+        SleepMode::Idle
+    } else if is_active(Adc) {
+        SleepMode::ADCNoiseReduction
+    } else {
+        SleepMode::PowerDown
+    }
+}
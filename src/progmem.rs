@@ -0,0 +1,296 @@
+//! Constant data stored in flash rather than RAM.
+//!
+//! Statics normally live in `.data`, which the startup code copies from
+//! flash into RAM.  Placing them in `.progmem.data` instead leaves them
+//! in flash, where they can only be read with the LPM instruction; a
+//! regular load through a pointer to such a static reads whatever
+//! happens to be at the same address in RAM.  `ProgMem<T>` wraps the
+//! value so that it can only be accessed via LPM.
+//!
+//! ```
+//! progmem! {
+//!     static KEYMAP: [[u8; 6]; 4] = [...];
+//!     static BANNER: [u8; 14] = *b"flutterby v0.1";
+//! }
+//!
+//! let row = KEYMAP.get(2).unwrap();
+//! simavr_logln!(BANNER.as_prog_str());
+//! ```
+//!
+//! Pointers are 16 bits wide, so only data in the lower 64KB of flash
+//! can be addressed.  That covers the whole of the atmega32u4's flash
+//! and the linker places `.progmem.data` ahead of the code.
+use core::marker::PhantomData;
+use core::mem;
+
+/// Declare one or more statics that are stored in flash.  Each
+/// `static NAME: T = value;` declares `NAME` as a `ProgMem<T>`.
+#[macro_export]
+macro_rules! progmem {
+    () => {};
+    ($(#[$attr:meta])* static $name:ident : $ty:ty = $value:expr ; $($rest:tt)*) => {
+        $(#[$attr])*
+        #[link_section = ".progmem.data"]
+        static $name: $crate::progmem::ProgMem<$ty> =
+            unsafe { $crate::progmem::ProgMem::new($value) };
+        progmem!($($rest)*);
+    };
+    ($(#[$attr:meta])* pub static $name:ident : $ty:ty = $value:expr ; $($rest:tt)*) => {
+        $(#[$attr])*
+        #[link_section = ".progmem.data"]
+        pub static $name: $crate::progmem::ProgMem<$ty> =
+            unsafe { $crate::progmem::ProgMem::new($value) };
+        progmem!($($rest)*);
+    };
+}
+
+/// Read a byte from flash
+#[inline(always)]
+pub unsafe fn read_byte(addr: *const u8) -> u8 {
+    let byte: u8;
+    asm!("lpm $0, Z"
+         : "=r"(byte)
+         : "z"(addr)
+         :
+         : "volatile");
+    byte
+}
+
+/// Copy `len` bytes from flash at `src` to RAM at `dest`
+pub unsafe fn read_bytes(src: *const u8, dest: *mut u8, len: usize) {
+    for i in 0..len {
+        *dest.offset(i as isize) = read_byte(src.offset(i as isize));
+    }
+}
+
+/// A value stored in flash.  Declare instances using the `progmem!`
+/// macro.
+pub struct ProgMem<T> {
+    value: T,
+}
+
+unsafe impl<T> Sync for ProgMem<T> {}
+
+impl<T> ProgMem<T> {
+    /// This is unsafe because the returned value must be placed in
+    /// the `.progmem.data` section, which `progmem!` takes care of.
+    pub const unsafe fn new(value: T) -> Self {
+        Self { value }
+    }
+
+    /// The flash address of the value
+    #[inline]
+    pub fn as_ptr(&self) -> *const T {
+        &self.value as *const T
+    }
+}
+
+impl<T: Copy> ProgMem<T> {
+    /// Copy the whole value into RAM
+    pub fn read(&self) -> T {
+        unsafe {
+            let mut value: T = mem::uninitialized();
+            read_bytes(
+                self.as_ptr() as *const u8,
+                &mut value as *mut T as *mut u8,
+                mem::size_of::<T>(),
+            );
+            value
+        }
+    }
+}
+
+/// Implemented for arrays so that the elements of a `ProgMem` array
+/// can be read individually rather than copying the whole array.
+pub unsafe trait Array {
+    type Item: Copy;
+    const LEN: usize;
+}
+
+macro_rules! impl_array {
+    ($($len:expr)*) => {
+        $(
+            unsafe impl<T: Copy> Array for [T; $len] {
+                type Item = T;
+                const LEN: usize = $len;
+            }
+        )*
+    };
+}
+
+impl_array!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26
+    27 28 29 30 31 32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50
+    51 52 53 54 55 56 57 58 59 60 61 62 63 64 65 66 67 68 69 70 71 72 73 74
+    75 76 77 78 79 80 81 82 83 84 85 86 87 88 89 90 91 92 93 94 95 96 97 98
+    99 100 101 102 103 104 105 106 107 108 109 110 111 112 113 114 115 116
+    117 118 119 120 121 122 123 124 125 126 127 128 192 256 512 1024
+);
+
+impl<A: Array> ProgMem<A> {
+    pub fn len(&self) -> usize {
+        A::LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        A::LEN == 0
+    }
+
+    /// Copy the element at `index` into RAM
+    pub fn get(&self, index: usize) -> Option<A::Item> {
+        if index >= A::LEN {
+            return None;
+        }
+        unsafe {
+            let mut item: A::Item = mem::uninitialized();
+            let size = mem::size_of::<A::Item>();
+            read_bytes(
+                (self.as_ptr() as *const u8).offset((index * size) as isize),
+                &mut item as *mut A::Item as *mut u8,
+                size,
+            );
+            Some(item)
+        }
+    }
+
+    pub fn iter(&self) -> Iter<A> {
+        Iter {
+            array: self,
+            index: 0,
+        }
+    }
+}
+
+impl<A: Array<Item = u8>> ProgMem<A> {
+    /// Treat a byte array as a string.  The contents are not checked,
+    /// so the array should hold valid UTF-8.
+    pub fn as_prog_str(&'static self) -> ProgStr {
+        ProgStr {
+            ptr: self.as_ptr() as *const u8,
+            len: A::LEN,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Erase the length of a byte array, so that arrays of different
+    /// sizes can be handled by the same code
+    pub fn as_prog_slice(&'static self) -> ProgSlice {
+        ProgSlice {
+            ptr: self.as_ptr() as *const u8,
+            len: A::LEN,
+            _marker: PhantomData,
+        }
+    }
+}
+
+pub struct Iter<'a, A: Array + 'a> {
+    array: &'a ProgMem<A>,
+    index: usize,
+}
+
+impl<'a, A: Array> Iterator for Iter<'a, A> {
+    type Item = A::Item;
+
+    fn next(&mut self) -> Option<A::Item> {
+        let item = self.array.get(self.index)?;
+        self.index += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = A::LEN - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+/// A string stored in flash
+#[derive(Copy, Clone)]
+pub struct ProgStr {
+    ptr: *const u8,
+    len: usize,
+    _marker: PhantomData<&'static [u8]>,
+}
+
+impl ProgStr {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn bytes(&self) -> ProgBytes {
+        ProgBytes {
+            ptr: self.ptr,
+            remaining: self.len,
+        }
+    }
+}
+
+/// A slice of bytes stored in flash
+#[derive(Copy, Clone)]
+pub struct ProgSlice {
+    ptr: *const u8,
+    len: usize,
+    _marker: PhantomData<&'static [u8]>,
+}
+
+impl ProgSlice {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<u8> {
+        if index >= self.len {
+            return None;
+        }
+        Some(unsafe { read_byte(self.ptr.offset(index as isize)) })
+    }
+
+    /// Returns the `len` bytes starting at `start`
+    pub fn slice(&self, start: usize, len: usize) -> Option<ProgSlice> {
+        if start > self.len || len > self.len - start {
+            return None;
+        }
+        Some(ProgSlice {
+            ptr: unsafe { self.ptr.offset(start as isize) },
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn bytes(&self) -> ProgBytes {
+        ProgBytes {
+            ptr: self.ptr,
+            remaining: self.len,
+        }
+    }
+}
+
+pub struct ProgBytes {
+    ptr: *const u8,
+    remaining: usize,
+}
+
+impl Iterator for ProgBytes {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let byte = unsafe { read_byte(self.ptr) };
+        self.ptr = unsafe { self.ptr.offset(1) };
+        self.remaining -= 1;
+        Some(byte)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
@@ -0,0 +1,112 @@
+//! PWM output on the Timer1 OC1A (PB5), OC1B (PB6) and OC1C (PB7) pins.
+//! This is handy for driving backlight LEDs.
+//!
+//! ```
+//! let pwm = Pwm::new(
+//!     timer1::Timer::new()
+//!         .waveform_generation_mode(timer1::WaveformGenerationMode::FastPwm8Bit)
+//!         .clock_source(timer1::ClockSource::Prescale8),
+//! ).unwrap();
+//! pwm.enable(Channel::B).unwrap();
+//! pwm.set_duty(Channel::B, pwm.max_duty() / 2);
+//! ```
+use mcu::{PortbSignalFlags, PORTB};
+use timer1::{self, Channel, CompareOutputMode};
+
+pub struct Pwm {
+    timer: timer1::Handle,
+    max_duty: u16,
+    /// The channel whose compare register defines TOP, if any.
+    /// That channel is not available for PWM output.
+    top_channel: Option<Channel>,
+}
+
+/// Returns the OC1x pin for a channel
+#[inline]
+fn channel_pin(channel: Channel) -> PortbSignalFlags {
+    match channel {
+        Channel::A => PortbSignalFlags::PB5,
+        Channel::B => PortbSignalFlags::PB6,
+        Channel::C => PortbSignalFlags::PB7,
+    }
+}
+
+impl Pwm {
+    /// Configure the timer and create a Pwm instance from it.
+    /// The timer must be configured for one of the PWM waveform
+    /// generation modes; if that mode uses ICR1 or OCR1A for TOP
+    /// then that value must also have been set.
+    /// All channels start out disabled.  Fails if something else
+    /// already owns Timer1.
+    pub fn new(timer: timer1::Timer) -> Result<Self, ()> {
+        if !timer.mode().is_pwm() {
+            return Err(());
+        }
+        let (max_duty, top_channel) = timer.top().ok_or(())?;
+        if !timer1::claim() {
+            return Err(());
+        }
+        Ok(Self {
+            timer: timer.configure(),
+            max_duty,
+            top_channel,
+        })
+    }
+
+    /// Returns the duty value corresponding to 100%
+    pub fn max_duty(&self) -> u16 {
+        self.max_duty
+    }
+
+    /// Returns the current duty value for a channel
+    pub fn duty(&self, channel: Channel) -> u16 {
+        self.timer.output_compare(channel)
+    }
+
+    /// Set the duty value for a channel; values larger than
+    /// max_duty() are clamped.
+    pub fn set_duty(&self, channel: Channel, duty: u16) {
+        if Some(channel) == self.top_channel {
+            return;
+        }
+        let duty = if duty > self.max_duty {
+            self.max_duty
+        } else {
+            duty
+        };
+        self.timer.set_output_compare(channel, duty);
+    }
+
+    /// Configure the pin for the channel as an output and connect
+    /// it to the timer in non-inverting mode.  Fails if the channel
+    /// is being used to define TOP.
+    pub fn enable(&self, channel: Channel) -> Result<(), ()> {
+        if Some(channel) == self.top_channel {
+            return Err(());
+        }
+        unsafe {
+            (*PORTB.get()).ddrb.modify(|x| x | channel_pin(channel));
+        }
+        self.timer
+            .set_compare_output_mode(channel, CompareOutputMode::Clear);
+        Ok(())
+    }
+
+    /// Disconnect the channel from its pin and drive the pin low
+    pub fn disable(&self, channel: Channel) {
+        if Some(channel) == self.top_channel {
+            return;
+        }
+        self.timer
+            .set_compare_output_mode(channel, CompareOutputMode::Disconnected);
+        unsafe {
+            (*PORTB.get()).portb.modify(|x| x - channel_pin(channel));
+        }
+    }
+}
+
+impl Drop for Pwm {
+    fn drop(&mut self) {
+        timer1::release();
+    }
+}
@@ -0,0 +1,89 @@
+//! A fixed capacity FIFO that can be placed in a static and shared
+//! between an interrupt handler and the event loop via a Mutex.
+//!
+//! ```
+//! static SAMPLES: Mutex<RingBuffer<u16, [u16; 8]>> = Mutex::new(RingBuffer::new([0; 8]));
+//! ```
+use core::marker::PhantomData;
+
+pub struct RingBuffer<T, A> {
+    buf: A,
+    /// Index of the oldest element
+    head: u8,
+    /// Number of elements currently stored
+    len: u8,
+    _item: PhantomData<T>,
+}
+
+impl<T, A> RingBuffer<T, A> {
+    /// Create a ring buffer using `buf` as its storage.  The
+    /// initial contents of `buf` are ignored.
+    pub const fn new(buf: A) -> Self {
+        Self {
+            buf,
+            head: 0,
+            len: 0,
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<T: Copy, A: AsRef<[T]> + AsMut<[T]>> RingBuffer<T, A> {
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.buf.as_ref().len()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    /// Discard all elements
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Append an element.  If the buffer is full the element is
+    /// handed back as the error value.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        let idx = (self.head as usize + self.len()) % self.capacity();
+        self.buf.as_mut()[idx] = item;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove and return the oldest element
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let item = self.buf.as_ref()[self.head as usize];
+        self.head = ((self.head as usize + 1) % self.capacity()) as u8;
+        self.len -= 1;
+        Some(item)
+    }
+
+    /// Returns the oldest element without removing it
+    pub fn peek(&self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.buf.as_ref()[self.head as usize])
+        }
+    }
+}
@@ -11,6 +11,7 @@
 use mcu;
 use core::fmt::{Error, Write};
 use core::mem;
+use progmem::ProgStr;
 
 #[allow(non_camel_case_types)]
 #[repr(u8)]
@@ -175,6 +176,18 @@ impl ConsoleWrite for str {
     }
 }
 
+impl ConsoleWrite for ProgStr {
+    fn write_to_console(&self) {
+        let console = unsafe { &(*mcu::simavr_regs::SIMAVR_CONSOLE.get()) };
+        for c in self.bytes() {
+            unsafe {
+                console.write(c);
+                asm!("NOP"::::"volatile");
+            }
+        }
+    }
+}
+
 
 /// Log a line to the simavr console using core::fmt
 /// core::fmt is nerfed in libcore at the moment, so
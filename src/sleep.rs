@@ -1,5 +1,6 @@
 use mcu;
 use core::ptr;
+use power;
 
 /// http://microchipdeveloper.com/8avr:avrsleep has more information on sleep modes
 pub enum SleepMode {
@@ -63,9 +64,17 @@ pub fn set_event_pending() {
     }
 }
 
-/// Put the CPU into sleep mode, blocking until an interrupt occurs.
+/// Put the CPU into the deepest sleep mode that won't stop any of the
+/// peripherals that are currently powered up, blocking until an interrupt
+/// occurs.
 /// Clears any pending event state that may have been set by set_event_pending().
-pub fn wait_for_event(mode: SleepMode) {
+pub fn wait_for_event() {
+    wait_for_event_in_mode(power::deepest_sleep_mode());
+}
+
+/// Put the CPU into the specified sleep mode, blocking until an interrupt occurs.
+/// Clears any pending event state that may have been set by set_event_pending().
+pub fn wait_for_event_in_mode(mode: SleepMode) {
     unsafe {
         set_sleep_mode(mode);
         asm!("CLI" :::: "volatile");
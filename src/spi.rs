@@ -0,0 +1,350 @@
+//! SPI master driver.  On the atmega32u4 the SPI pins are
+//! SS (PB0), SCK (PB1), MOSI (PB2) and MISO (PB3).
+//!
+//! In master mode the hardware drops back to slave mode if SS is an
+//! input and is pulled low, so the SS pin is always configured as an
+//! output driven high.  It can still be used as a chip select.
+//!
+//! Both blocking transfers and interrupt driven futures are provided.
+//! The futures take ownership of the Spi and hand it back on completion
+//! so that only one transfer can be in flight at a time:
+//!
+//! ```
+//! let spi = Spi::new(Config::new().clock_divider(ClockDivider::Div4));
+//! events.spawn(spi.write_async(&FRAME[..]).map(|(spi, _buf)| { ... }))?;
+//! ```
+use mcu::{PortbSignalFlags, SpiSpcrFlags, SpiSpsrFlags, PORTB, SPI};
+use mutex::Mutex;
+use ringbuffer::RingBuffer;
+use power::{self, PowerHandle};
+use futures::{Async, Future, Poll};
+use sleep;
+
+const SPIE: SpiSpcrFlags = SpiSpcrFlags::from_bits(1 << 7);
+const SPE: SpiSpcrFlags = SpiSpcrFlags::from_bits(1 << 6);
+const DORD: SpiSpcrFlags = SpiSpcrFlags::from_bits(1 << 5);
+const MSTR: SpiSpcrFlags = SpiSpcrFlags::from_bits(1 << 4);
+const CPOL: SpiSpcrFlags = SpiSpcrFlags::from_bits(1 << 3);
+const CPHA: SpiSpcrFlags = SpiSpcrFlags::from_bits(1 << 2);
+
+const SPIF: SpiSpsrFlags = SpiSpsrFlags::from_bits(1 << 7);
+const SPI2X: SpiSpsrFlags = SpiSpsrFlags::from_bits(1 << 0);
+
+const SS: PortbSignalFlags = PortbSignalFlags::PB0;
+const SCK: PortbSignalFlags = PortbSignalFlags::PB1;
+const MOSI: PortbSignalFlags = PortbSignalFlags::PB2;
+
+/// Clock polarity and phase
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Idle low, sample on the leading (rising) edge
+    Mode0,
+    /// Idle low, sample on the trailing (falling) edge
+    Mode1,
+    /// Idle high, sample on the leading (falling) edge
+    Mode2,
+    /// Idle high, sample on the trailing (rising) edge
+    Mode3,
+}
+
+impl Mode {
+    #[inline]
+    fn bits(&self) -> SpiSpcrFlags {
+        match *self {
+            Mode::Mode0 => SpiSpcrFlags::empty(),
+            Mode::Mode1 => CPHA,
+            Mode::Mode2 => CPOL,
+            Mode::Mode3 => CPOL | CPHA,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+/// Divides the CPU clock to produce SCK
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClockDivider {
+    Div2,
+    Div4,
+    Div8,
+    Div16,
+    Div32,
+    Div64,
+    Div128,
+}
+
+impl ClockDivider {
+    /// Returns the SPR1:0 bits and whether SPI2X is needed
+    #[inline]
+    fn bits(&self) -> (SpiSpcrFlags, bool) {
+        use self::ClockDivider::*;
+        match *self {
+            Div2 => (SpiSpcrFlags::from_bits(0b00), true),
+            Div4 => (SpiSpcrFlags::from_bits(0b00), false),
+            Div8 => (SpiSpcrFlags::from_bits(0b01), true),
+            Div16 => (SpiSpcrFlags::from_bits(0b01), false),
+            Div32 => (SpiSpcrFlags::from_bits(0b10), true),
+            Div64 => (SpiSpcrFlags::from_bits(0b10), false),
+            Div128 => (SpiSpcrFlags::from_bits(0b11), false),
+        }
+    }
+}
+
+/// SPI configuration; defaults to mode 0, MSB first, SCK = F_CPU / 4
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    mode: Mode,
+    bit_order: BitOrder,
+    clock_divider: ClockDivider,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Mode0,
+            bit_order: BitOrder::MsbFirst,
+            clock_divider: ClockDivider::Div4,
+        }
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn bit_order(mut self, bit_order: BitOrder) -> Self {
+        self.bit_order = bit_order;
+        self
+    }
+
+    pub fn clock_divider(mut self, clock_divider: ClockDivider) -> Self {
+        self.clock_divider = clock_divider;
+        self
+    }
+}
+
+const BUFFER_SIZE: usize = 16;
+
+/// Bytes queued for the interrupt driven transfers.  The interrupt
+/// handler sends the next byte from `tx` each time a byte completes
+/// and, for transfers, stores the received byte in `rx`.
+struct Queue {
+    tx: RingBuffer<u8, [u8; BUFFER_SIZE]>,
+    rx: RingBuffer<u8, [u8; BUFFER_SIZE]>,
+    keep_rx: bool,
+    busy: bool,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    tx: RingBuffer::new([0; BUFFER_SIZE]),
+    rx: RingBuffer::new([0; BUFFER_SIZE]),
+    keep_rx: false,
+    busy: false,
+});
+
+fn spi_stc() {
+    let mut queue = QUEUE.lock();
+    unsafe {
+        let spi = &(*SPI.get());
+        let byte = spi.spdr.read();
+        if queue.keep_rx {
+            // The future only queues as many bytes as there is room
+            // to receive, so this cannot fail.
+            let _ = queue.rx.push(byte);
+        }
+        match queue.tx.pop() {
+            Some(next) => spi.spdr.write(next),
+            None => queue.busy = false,
+        }
+    }
+    sleep::set_event_pending();
+}
+
+irq_handler!(SPI_STC, spi_stc);
+
+pub struct Spi {
+    _power: PowerHandle,
+}
+
+impl Spi {
+    /// Configure the pins and enable the SPI in master mode
+    pub fn new(config: Config) -> Self {
+        let power = power::acquire(power::Peripheral::Spi);
+        let (spr, double) = config.clock_divider.bits();
+        let mut spcr = SPE | MSTR | config.mode.bits() | spr;
+        if config.bit_order == BitOrder::LsbFirst {
+            spcr |= DORD;
+        }
+
+        unsafe {
+            let portb = &(*PORTB.get());
+            // Drive SS high before making it an output so that
+            // an attached device is not selected
+            portb.portb.modify(|x| x | SS);
+            portb.ddrb.modify(|x| x | SS | SCK | MOSI);
+
+            let spi = &(*SPI.get());
+            spi.spcr.write(spcr);
+            spi.spsr.write(if double {
+                SPI2X
+            } else {
+                SpiSpsrFlags::empty()
+            });
+        }
+
+        {
+            let mut queue = QUEUE.lock();
+            queue.tx.clear();
+            queue.rx.clear();
+            queue.busy = false;
+        }
+
+        Self { _power: power }
+    }
+
+    /// Send a byte and return the byte that was received at the same time
+    pub fn transfer_byte(&mut self, byte: u8) -> u8 {
+        unsafe {
+            let spi = &(*SPI.get());
+            spi.spdr.write(byte);
+            while (spi.spsr.read() & SPIF) != SPIF {}
+            spi.spdr.read()
+        }
+    }
+
+    /// Exchange the contents of `buf` with the device, blocking
+    /// until complete
+    pub fn transfer(&mut self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            *byte = self.transfer_byte(*byte);
+        }
+    }
+
+    /// Send the contents of `buf`, discarding the received bytes,
+    /// blocking until complete
+    pub fn write(&mut self, buf: &[u8]) {
+        for byte in buf.iter() {
+            self.transfer_byte(*byte);
+        }
+    }
+
+    /// Returns a Future that exchanges the contents of `buf` with the
+    /// device using the SPI interrupt, and then resolves to the Spi and
+    /// the buffer holding the received data.
+    pub fn transfer_async<B: AsMut<[u8]>>(self, buf: B) -> Transfer<B> {
+        Transfer {
+            spi: Some(self),
+            buf: Some(buf),
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// Returns a Future that sends the contents of `buf` using the SPI
+    /// interrupt, and then resolves to the Spi and the buffer.
+    pub fn write_async<B: AsRef<[u8]>>(self, buf: B) -> Write<B> {
+        Write {
+            spi: Some(self),
+            buf: Some(buf),
+            sent: 0,
+        }
+    }
+}
+
+impl Drop for Spi {
+    fn drop(&mut self) {
+        unsafe {
+            (*SPI.get()).spcr.write(SpiSpcrFlags::empty());
+        }
+    }
+}
+
+/// Queue bytes from `data` starting at `pos` and start the transfer
+/// if the hardware is idle.  Returns the new value of `pos`.
+fn fill_queue(queue: &mut Queue, data: &[u8], mut pos: usize, keep_rx: bool) -> usize {
+    queue.keep_rx = keep_rx;
+    while pos < data.len() && !queue.tx.is_full() {
+        // For transfers, leave room to receive everything in flight
+        if keep_rx && queue.tx.len() + queue.rx.len() + queue.busy as usize >= BUFFER_SIZE {
+            break;
+        }
+        let _ = queue.tx.push(data[pos]);
+        pos += 1;
+    }
+    if !queue.busy {
+        if let Some(first) = queue.tx.pop() {
+            queue.busy = true;
+            unsafe {
+                let spi = &(*SPI.get());
+                spi.spcr.modify(|x| x | SPIE);
+                spi.spdr.write(first);
+            }
+        }
+    }
+    pos
+}
+
+/// Disable the interrupt once an async operation has completed,
+/// so that the blocking functions can poll SPIF again
+fn finish() {
+    unsafe {
+        (*SPI.get()).spcr.modify(|x| x - SPIE);
+    }
+}
+
+pub struct Transfer<B> {
+    spi: Option<Spi>,
+    buf: Option<B>,
+    sent: usize,
+    received: usize,
+}
+
+impl<B: AsMut<[u8]>> Future for Transfer<B> {
+    type Item = (Spi, B);
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(Spi, B), ()> {
+        {
+            let data = self.buf.as_mut().expect("polled after completion").as_mut();
+            let mut queue = QUEUE.lock();
+            while let Some(byte) = queue.rx.pop() {
+                data[self.received] = byte;
+                self.received += 1;
+            }
+            self.sent = fill_queue(&mut queue, data, self.sent, true);
+            if self.received < data.len() {
+                return Ok(Async::NotReady);
+            }
+        }
+        finish();
+        Ok(Async::Ready((self.spi.take().unwrap(), self.buf.take().unwrap())))
+    }
+}
+
+pub struct Write<B> {
+    spi: Option<Spi>,
+    buf: Option<B>,
+    sent: usize,
+}
+
+impl<B: AsRef<[u8]>> Future for Write<B> {
+    type Item = (Spi, B);
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(Spi, B), ()> {
+        {
+            let data = self.buf.as_ref().expect("polled after completion").as_ref();
+            let mut queue = QUEUE.lock();
+            self.sent = fill_queue(&mut queue, data, self.sent, false);
+            if self.sent < data.len() || queue.busy {
+                return Ok(Async::NotReady);
+            }
+        }
+        finish();
+        Ok(Async::Ready((self.spi.take().unwrap(), self.buf.take().unwrap())))
+    }
+}
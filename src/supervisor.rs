@@ -0,0 +1,240 @@
+//! Watchdog based supervision of event loop tasks.
+//! A single future that never yields would otherwise stall the whole
+//! firmware with nothing to notice.  The Supervisor arms the watchdog
+//! and only resets its counter while every registered critical task
+//! has checked in within its deadline.  When a task starves, the
+//! watchdog is allowed to expire and reset the MCU; the identity of
+//! the starved task(s) is kept in memory that is not initialized at
+//! startup so that it can be reported by reset_cause() after reboot.
+use arrayvec::ArrayVec;
+use core::ptr::{read_volatile, write_volatile};
+use eventloop::{Ticks, TICK_PERIOD_MS};
+use mcu::CpuMcusrFlags;
+use mutex::CriticalSection;
+use wdt;
+
+/// Identifies a task registered with the Supervisor
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TaskId(u8);
+
+impl TaskId {
+    /// Returns the index of the task in the order that it was registered
+    pub fn index(&self) -> u8 {
+        self.0
+    }
+
+    #[inline]
+    fn mask(&self) -> u8 {
+        1 << self.0
+    }
+
+    /// Record that the task is making progress.  Call this from the
+    /// task at least once per deadline to prevent the supervisor
+    /// from allowing the watchdog to reset the system.
+    pub fn checkin(&self) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            write_volatile(&mut CHECKED_IN, read_volatile(&CHECKED_IN) | self.mask());
+        }
+    }
+}
+
+/// Bitmask of tasks that have checked in since the last supervision pass
+static mut CHECKED_IN: u8 = 0;
+/// Bitmask of the tasks registered with the active supervisor
+static mut REGISTERED: u8 = 0;
+
+const STARVED_MAGIC: u8 = 0xa5;
+
+/// Survives a watchdog reset because .noinit is not touched
+/// by the startup code.
+#[repr(C)]
+struct StarvedRecord {
+    magic: u8,
+    mask: u8,
+}
+
+#[link_section = ".noinit"]
+static mut STARVED: StarvedRecord = StarvedRecord { magic: 0, mask: 0 };
+
+fn record_starved(mask: u8) {
+    let _cs = CriticalSection::new();
+    unsafe {
+        let prior = if read_volatile(&STARVED.magic) == STARVED_MAGIC {
+            read_volatile(&STARVED.mask)
+        } else {
+            0
+        };
+        write_volatile(&mut STARVED.mask, prior | mask);
+        write_volatile(&mut STARVED.magic, STARVED_MAGIC);
+    }
+}
+
+/// Called from the watchdog interrupt one interval before the watchdog
+/// is going to reset the system.  If the event loop itself is stuck
+/// then the supervision pass never runs, so blame every task that has
+/// not checked in since the last supervision pass.  The hardware has
+/// disabled the interrupt; Supervisor::supervise() re-arms it and
+/// discards this record if the event loop recovers in time.
+fn last_gasp() {
+    unsafe {
+        let pending = read_volatile(&REGISTERED) & !read_volatile(&CHECKED_IN);
+        record_starved(pending);
+    }
+}
+
+/// Describes why the system was last reset
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ResetCause {
+    /// The MCUSR flags as they were at startup
+    pub flags: CpuMcusrFlags,
+    /// Bitmask of the TaskId indices that were starved, if the reset
+    /// was caused by the supervisor
+    pub starved: u8,
+}
+
+impl ResetCause {
+    /// Returns true if the supervisor allowed the watchdog to
+    /// reset the system
+    pub fn is_supervisor_reset(&self) -> bool {
+        self.starved != 0
+    }
+
+    /// Returns true if the task was starved at the time of the reset
+    pub fn starved_task(&self, task: TaskId) -> bool {
+        self.starved & task.mask() != 0
+    }
+}
+
+/// Returns the cause of the most recent reset.
+/// The starved task information is discarded when the supervisor
+/// is started, so call this before EventLoop::run().
+pub fn reset_cause() -> ResetCause {
+    let flags = wdt::reset_flags();
+    let starved = unsafe {
+        if (flags & CpuMcusrFlags::WDRF) == CpuMcusrFlags::WDRF
+            && read_volatile(&STARVED.magic) == STARVED_MAGIC
+        {
+            read_volatile(&STARVED.mask)
+        } else {
+            0
+        }
+    };
+    ResetCause { flags, starved }
+}
+
+struct Task {
+    deadline: Ticks,
+    remaining: Ticks,
+}
+
+/// Supervisor is passed to EventLoop::set_supervisor().
+/// The event loop arms the watchdog when it starts running and
+/// calls into the supervisor after each turn.
+pub struct Supervisor {
+    timeout: wdt::Duration,
+    tasks: ArrayVec<[Task; 8]>,
+    starved: u8,
+}
+
+impl Supervisor {
+    /// Create a supervisor that will allow the watchdog to reset the
+    /// system after `timeout` has elapsed without all of the tasks
+    /// having checked in.  Returns None if `timeout` is shorter than
+    /// the event loop tick, as the watchdog would expire between turns
+    /// of an idle event loop, or if a wdt::Ticker or another
+    /// Supervisor already owns the watchdog.
+    pub fn new(timeout: wdt::Duration) -> Option<Self> {
+        if (timeout as u16) < TICK_PERIOD_MS || !wdt::claim() {
+            return None;
+        }
+        Some(Self {
+            timeout,
+            tasks: ArrayVec::new(),
+            starved: 0,
+        })
+    }
+
+    /// Register a critical task that must checkin() at least once per
+    /// `deadline`.  Fails if the maximum of 8 tasks are already registered.
+    pub fn add_task(&mut self, deadline: Ticks) -> Result<TaskId, ()> {
+        let id = TaskId(self.tasks.len() as u8);
+        self.tasks
+            .try_push(Task {
+                deadline,
+                remaining: deadline,
+            })
+            .map_err(|_| ())?;
+        Ok(id)
+    }
+
+    /// Arms the watchdog.  Called when the event loop starts running.
+    pub(crate) fn start(&mut self) {
+        {
+            let _cs = CriticalSection::new();
+            unsafe {
+                write_volatile(&mut CHECKED_IN, 0);
+                write_volatile(&mut REGISTERED, ((1u16 << self.tasks.len()) - 1) as u8);
+                // Forget about any prior starvation now that we're up and running
+                write_volatile(&mut STARVED.magic, 0);
+            }
+        }
+        wdt::enable_interrupt_then_reset(self.timeout, last_gasp);
+    }
+
+    /// Called by the event loop after each turn.  Resets the watchdog
+    /// counter only if no task has missed its deadline.
+    pub(crate) fn supervise(&mut self, elapsed: Ticks) {
+        let checked_in = {
+            let _cs = CriticalSection::new();
+            unsafe {
+                let checked_in = read_volatile(&CHECKED_IN);
+                write_volatile(&mut CHECKED_IN, 0);
+                checked_in
+            }
+        };
+
+        for (idx, task) in self.tasks.iter_mut().enumerate() {
+            let mask = 1 << idx;
+            if checked_in & mask != 0 {
+                task.remaining = task.deadline;
+            } else if elapsed >= task.remaining {
+                if self.starved & mask == 0 {
+                    logln!("supervisor: task ", idx, " starved");
+                }
+                self.starved |= mask;
+            } else {
+                task.remaining -= elapsed;
+            }
+        }
+
+        if self.starved != 0 {
+            // Let the watchdog expire and take the system down
+            record_starved(self.starved);
+            return;
+        }
+
+        wdt::reset();
+
+        // If last_gasp() fired then the event loop has since recovered
+        // in time; discard its record and re-arm the interrupt, which
+        // the hardware disabled when it fired.
+        let recovered = {
+            let _cs = CriticalSection::new();
+            unsafe {
+                let recovered = read_volatile(&STARVED.magic) == STARVED_MAGIC;
+                write_volatile(&mut STARVED.magic, 0);
+                recovered
+            }
+        };
+        if recovered {
+            wdt::enable_interrupt_then_reset(self.timeout, last_gasp);
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        wdt::release();
+    }
+}
@@ -0,0 +1,137 @@
+//! System level control: resetting the MCU and handing control
+//! over to the bootloader so that new firmware can be flashed
+//! without pressing the reset button.
+use mcu;
+use clock;
+use fcpu::busy_wait_ms;
+#[cfg(AVR_WDT)]
+use wdt;
+
+#[cfg(all(feature = "feather32u4", feature = "teensy2"))]
+compile_error!("choose one board feature: feather32u4 or teensy2");
+
+/// Byte address of the Caterina bootloader on the feather32u4
+#[cfg(feature = "feather32u4")]
+pub const BOOTLOADER_ADDR: u16 = 0x7000;
+
+/// Byte address of the HalfKay bootloader on the teensy2
+#[cfg(feature = "teensy2")]
+pub const BOOTLOADER_ADDR: u16 = 0x7e00;
+
+/// Reset the MCU by enabling the watchdog with its shortest
+/// interval and waiting for it to expire.
+#[cfg(AVR_WDT)]
+pub fn reset() -> ! {
+    unsafe {
+        asm!("CLI"::::"volatile");
+    }
+    // Hand over at the full oscillator frequency, which is what the
+    // bootloader and our own startup code expect to find.
+    clock::set_prescaler(clock::Prescaler::Div1);
+    wdt::enable(wdt::Duration::Approx15ms);
+    loop {}
+}
+
+/// Turn off the peripherals that this crate may have configured so
+/// that they don't raise interrupts or continue to drive the bus
+/// while the bootloader is running.  Interrupts must be disabled
+/// before calling this.
+#[cfg(any(feature = "feather32u4", feature = "teensy2"))]
+unsafe fn shutdown_peripherals() {
+    // power::initialize() gated off the clocks of every peripheral we
+    // weren't using.  The bootloader expects the reset defaults, and
+    // writes to a peripheral are ignored while its clock is gated, so
+    // ungate everything before touching the registers below.
+    let cpu = &(*mcu::CPU.get());
+    cpu.prr0.write(mcu::CpuPrr0Flags::empty());
+    cpu.prr1.write(mcu::CpuPrr1Flags::empty());
+
+    #[cfg(AVR_USB_DEVICE)]
+    {
+        let usb = &(*mcu::USB_DEVICE.get());
+        // Detach from the bus and freeze the USB clock
+        usb.udcon.write(mcu::UsbDeviceUdconFlags::DETACH);
+        usb.usbcon.write(mcu::UsbDeviceUsbconFlags::FRZCLK);
+    }
+    #[cfg(AVR_PLL)]
+    (*mcu::PLL.get()).pllcsr.write(mcu::PllPllcsrFlags::empty());
+
+    #[cfg(AVR_WDT)]
+    wdt::disable();
+
+    #[cfg(AVR_TC0)]
+    {
+        let tc0 = &(*mcu::TC0.get());
+        tc0.timsk0.write(mcu::Tc0Timsk0Flags::empty());
+        tc0.tccr0a.write(mcu::Tc0Tccr0aFlags::empty());
+        tc0.tccr0b.write(mcu::Tc0Tccr0bFlags::empty());
+    }
+
+    let tc1 = &(*mcu::TC1.get());
+    tc1.timsk1.write(mcu::Tc1Timsk1Flags::empty());
+    tc1.tccr1a.write(mcu::Tc1Tccr1aFlags::empty());
+    tc1.tccr1b.write(mcu::Tc1Tccr1bFlags::empty());
+
+    #[cfg(AVR_TC3)]
+    {
+        let tc3 = &(*mcu::TC3.get());
+        tc3.timsk3.write(mcu::Tc3Timsk3Flags::empty());
+        tc3.tccr3a.write(mcu::Tc3Tccr3aFlags::empty());
+        tc3.tccr3b.write(mcu::Tc3Tccr3bFlags::empty());
+    }
+
+    #[cfg(AVR_TC4)]
+    {
+        let tc4 = &(*mcu::TC4.get());
+        tc4.timsk4.write(mcu::Tc4Timsk4Flags::empty());
+        tc4.tccr4a.write(mcu::Tc4Tccr4aFlags::empty());
+        tc4.tccr4b.write(mcu::Tc4Tccr4bFlags::empty());
+    }
+
+    #[cfg(AVR_USART1)]
+    (*mcu::USART1.get())
+        .ucsr1b
+        .write(mcu::Usart1Ucsr1bFlags::empty());
+
+    #[cfg(AVR_SPI)]
+    (*mcu::SPI.get()).spcr.write(mcu::SpiSpcrFlags::empty());
+
+    #[cfg(AVR_TWI)]
+    (*mcu::TWI.get()).twcr.write(mcu::TwiTwcrFlags::empty());
+
+    #[cfg(AVR_ADC)]
+    (*mcu::ADC.get()).adcsra.write(mcu::AdcAdcsraFlags::empty());
+
+    #[cfg(AVR_EEPROM)]
+    (*mcu::EEPROM.get()).eecr.write(mcu::EepromEecrFlags::empty());
+
+    #[cfg(AVR_EXINT)]
+    {
+        let exint = &(*mcu::EXINT.get());
+        exint.eimsk.write(mcu::ExintEimskFlags::empty());
+        exint.pcicr.write(mcu::ExintPcicrFlags::empty());
+    }
+
+    // Let the host notice that we went away before the bootloader
+    // re-attaches to the bus.
+    busy_wait_ms(5);
+
+    // The bootloaders look at the reset flags to decide whether to
+    // start the application; clear them so that they stay resident.
+    cpu.mcusr.write(mcu::CpuMcusrFlags::empty());
+}
+
+/// Disable USB, timers and interrupts and then jump to the
+/// bootloader so that the board enters flash mode.
+#[cfg(any(feature = "feather32u4", feature = "teensy2"))]
+pub fn jump_to_bootloader() -> ! {
+    unsafe {
+        asm!("CLI"::::"volatile");
+        // The bootloader's USB and delay code assume the full
+        // oscillator frequency, as does busy_wait_ms() below.
+        clock::set_prescaler(clock::Prescaler::Div1);
+        shutdown_peripherals();
+        asm!("JMP $0" :: "i"(BOOTLOADER_ADDR) :: "volatile");
+    }
+    loop {}
+}
@@ -0,0 +1,412 @@
+//! Timer0 is an 8-bit timer with two compare units.
+//! OC0A is connected to PB7 and OC0B to PD0 on the atmega32u4.
+use mcu::{TC0, Tc0Tccr0aFlags, Tc0Tccr0bFlags, Tc0Timsk0Flags};
+use mutex::{interrupt_free, CriticalSection};
+use power::{self, PowerHandle};
+use core::ptr::{read_volatile, write_volatile};
+use clock;
+use eventloop::TickSource;
+use timer1;
+pub use timer1::{ClockSource, CompareOutputMode};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WaveformGenerationMode {
+    Normal,
+    PwmPhaseCorrect,
+    ClearOnTimerMatchOutputCompare,
+    FastPwm,
+    PwmPhaseCorrectOutputCompare,
+    FastPwmOutputCompare,
+}
+
+const WGM00: Tc0Tccr0aFlags = Tc0Tccr0aFlags::from_bits(1 << 0);
+const WGM01: Tc0Tccr0aFlags = Tc0Tccr0aFlags::from_bits(1 << 1);
+
+const WGM02: Tc0Tccr0bFlags = Tc0Tccr0bFlags::from_bits(1 << 3);
+
+const FOC0A: Tc0Tccr0bFlags = Tc0Tccr0bFlags::from_bits(1 << 7);
+const FOC0B: Tc0Tccr0bFlags = Tc0Tccr0bFlags::from_bits(1 << 6);
+
+impl WaveformGenerationMode {
+    #[inline]
+    fn bits(&self) -> (Tc0Tccr0bFlags, Tc0Tccr0aFlags) {
+        use self::WaveformGenerationMode::*;
+        match *self {
+            Normal => (Tc0Tccr0bFlags::empty(), Tc0Tccr0aFlags::empty()),
+            PwmPhaseCorrect => (Tc0Tccr0bFlags::empty(), WGM00),
+            ClearOnTimerMatchOutputCompare => (Tc0Tccr0bFlags::empty(), WGM01),
+            FastPwm => (Tc0Tccr0bFlags::empty(), WGM01 | WGM00),
+            // Reserved                     => (WGM02, Tc0Tccr0aFlags::empty()),
+            PwmPhaseCorrectOutputCompare => (WGM02, WGM00),
+            // Reserved                     => (WGM02, WGM01),
+            FastPwmOutputCompare => (WGM02, WGM01 | WGM00),
+        }
+    }
+
+    /// Returns true if TOP is defined by the OCR0A register
+    #[inline]
+    fn top_is_output_compare(&self) -> bool {
+        use self::WaveformGenerationMode::*;
+        match *self {
+            ClearOnTimerMatchOutputCompare
+            | PwmPhaseCorrectOutputCompare
+            | FastPwmOutputCompare => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true for the fast and phase correct PWM modes
+    pub fn is_pwm(&self) -> bool {
+        use self::WaveformGenerationMode::*;
+        match *self {
+            Normal | ClearOnTimerMatchOutputCompare => false,
+            _ => true,
+        }
+    }
+}
+
+/// Selects one of the two output compare units
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channel {
+    A,
+    B,
+}
+
+impl Channel {
+    #[inline]
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// The compare output mode bits have the same layout as
+    /// for channels A and B of Timer1.
+    #[inline]
+    fn timer1_channel(&self) -> timer1::Channel {
+        match *self {
+            Channel::A => timer1::Channel::A,
+            Channel::B => timer1::Channel::B,
+        }
+    }
+
+    #[inline]
+    fn com_bits(&self, mode: CompareOutputMode) -> Tc0Tccr0aFlags {
+        Tc0Tccr0aFlags::from_bits(mode.bits(self.timer1_channel()).bits())
+    }
+
+    #[inline]
+    fn com_mask(&self) -> Tc0Tccr0aFlags {
+        self.com_bits(CompareOutputMode::Set)
+    }
+
+    #[inline]
+    fn force_bits(&self) -> Tc0Tccr0bFlags {
+        match *self {
+            Channel::A => FOC0A,
+            Channel::B => FOC0B,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    CompareA,
+    CompareB,
+    Overflow,
+}
+
+const NUM_INTERRUPTS: usize = 3;
+
+impl Interrupt {
+    #[inline]
+    fn bits(&self) -> Tc0Timsk0Flags {
+        use self::Interrupt::*;
+        match *self {
+            CompareA => Tc0Timsk0Flags::OCIE0A,
+            CompareB => Tc0Timsk0Flags::OCIE0B,
+            Overflow => Tc0Timsk0Flags::TOIE0,
+        }
+    }
+
+    #[inline]
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// The functions to call from the timer0 interrupt vectors
+static mut HANDLERS: [Option<fn()>; NUM_INTERRUPTS] = [None; NUM_INTERRUPTS];
+
+/// Register a function to be called from the interrupt vector.
+/// The interrupt itself is enabled via Timer::interrupt() or
+/// Handle::enable_interrupt().
+pub fn set_interrupt_handler(interrupt: Interrupt, handler: Option<fn()>) {
+    interrupt_free(|_cs| unsafe {
+        write_volatile(&mut HANDLERS[interrupt.index()], handler);
+    });
+}
+
+#[inline]
+fn dispatch(interrupt: Interrupt) {
+    unsafe {
+        if let Some(handler) = read_volatile(&HANDLERS[interrupt.index()]) {
+            handler();
+        }
+    }
+}
+
+fn timer0_compare_a() {
+    dispatch(Interrupt::CompareA);
+}
+
+fn timer0_compare_b() {
+    dispatch(Interrupt::CompareB);
+}
+
+fn timer0_overflow() {
+    dispatch(Interrupt::Overflow);
+}
+
+irq_handler!(TIMER0_COMPA, timer0_compare_a);
+irq_handler!(TIMER0_COMPB, timer0_compare_b);
+irq_handler!(TIMER0_OVF, timer0_overflow);
+
+pub struct Timer {
+    wgm: WaveformGenerationMode,
+    a: Tc0Tccr0aFlags,
+    b: Tc0Tccr0bFlags,
+    compare: [Option<u8>; 2],
+    interrupts: Tc0Timsk0Flags,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            wgm: WaveformGenerationMode::Normal,
+            a: Tc0Tccr0aFlags::empty(),
+            b: Tc0Tccr0bFlags::empty(),
+            compare: [None; 2],
+            interrupts: Tc0Timsk0Flags::empty(),
+        }
+    }
+
+    pub fn waveform_generation_mode(mut self, wgm: WaveformGenerationMode) -> Self {
+        let (b, a) = wgm.bits();
+
+        self.a |= a;
+        self.b |= b;
+        self.wgm = wgm;
+
+        self
+    }
+
+    /// Returns the most recently selected waveform generation mode
+    pub fn mode(&self) -> WaveformGenerationMode {
+        self.wgm
+    }
+
+    /// Returns the value of TOP for the configured waveform generation
+    /// mode along with the channel whose compare register is used to
+    /// define it, if any.  Returns None if TOP is defined by OCR0A
+    /// and that has not been set.
+    pub fn top(&self) -> Option<(u8, Option<Channel>)> {
+        if self.wgm.top_is_output_compare() {
+            self.compare[Channel::A.index()].map(|top| (top, Some(Channel::A)))
+        } else {
+            Some((0xff, None))
+        }
+    }
+
+    pub fn clock_source(mut self, src: ClockSource) -> Self {
+        // The CS0 bits have the same layout as the CS1 bits
+        self.b |= Tc0Tccr0bFlags::from_bits(src.bits().bits());
+        self
+    }
+
+    /// Set the compare value for a channel
+    pub fn output_compare(mut self, channel: Channel, value: u8) -> Self {
+        self.compare[channel.index()] = Some(value);
+        self
+    }
+
+    /// Set the behavior of the OC0x pin for a channel.  The pin must
+    /// also be configured as an output for the waveform to be visible.
+    pub fn compare_output_mode(mut self, channel: Channel, mode: CompareOutputMode) -> Self {
+        self.a -= channel.com_mask();
+        self.a |= channel.com_bits(mode);
+        self
+    }
+
+    /// Enable an interrupt.  Use set_interrupt_handler() to register
+    /// the function that will be called from the interrupt.
+    pub fn interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.interrupts |= interrupt.bits();
+        self
+    }
+
+    /// Apply the configuration to the hardware.  The timer's clock
+    /// remains enabled until the returned Handle is dropped.
+    /// Interrupts that were enabled prior to this call are left enabled.
+    pub fn configure(self) -> Handle {
+        let power = power::acquire(power::Peripheral::Timer0);
+        unsafe {
+            interrupt_free(|_cs| {
+                let tc0 = &(*TC0.get());
+                tc0.tccr0a.write(self.a);
+                tc0.tccr0b.write(self.b);
+                tc0.tcnt0.write(0);
+
+                if let Some(compare) = self.compare[Channel::A.index()] {
+                    tc0.ocr0a.write(compare);
+                }
+                if let Some(compare) = self.compare[Channel::B.index()] {
+                    tc0.ocr0b.write(compare);
+                }
+
+                tc0.timsk0.modify(|x| x | self.interrupts);
+            });
+        }
+        Handle { _power: power }
+    }
+}
+
+/// Represents a configured Timer0.  Provides access to the
+/// counter and compare registers without reconfiguring the timer.
+#[must_use]
+pub struct Handle {
+    _power: PowerHandle,
+}
+
+impl Handle {
+    /// Returns the current value of TCNT0
+    pub fn counter(&self) -> u8 {
+        unsafe { (*TC0.get()).tcnt0.read() }
+    }
+
+    pub fn set_counter(&self, value: u8) {
+        unsafe { (*TC0.get()).tcnt0.write(value) }
+    }
+
+    /// Returns the compare value for a channel
+    pub fn output_compare(&self, channel: Channel) -> u8 {
+        unsafe {
+            let tc0 = &(*TC0.get());
+            match channel {
+                Channel::A => tc0.ocr0a.read(),
+                Channel::B => tc0.ocr0b.read(),
+            }
+        }
+    }
+
+    /// Update the compare value for a channel.  In the PWM modes
+    /// the hardware double buffers this until the counter reaches TOP
+    /// or BOTTOM.
+    pub fn set_output_compare(&self, channel: Channel, value: u8) {
+        unsafe {
+            let tc0 = &(*TC0.get());
+            match channel {
+                Channel::A => tc0.ocr0a.write(value),
+                Channel::B => tc0.ocr0b.write(value),
+            }
+        }
+    }
+
+    pub fn set_compare_output_mode(&self, channel: Channel, mode: CompareOutputMode) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC0.get())
+                .tccr0a
+                .modify(|x| (x - channel.com_mask()) | channel.com_bits(mode));
+        }
+    }
+
+    /// Strobe a compare match for a channel without setting the
+    /// interrupt flag or resetting the counter.  Only valid in
+    /// the non-PWM modes.
+    pub fn force_output_compare(&self, channel: Channel) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC0.get()).tccr0b.modify(|x| x | channel.force_bits());
+        }
+    }
+
+    pub fn enable_interrupt(&self, interrupt: Interrupt) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC0.get()).timsk0.modify(|x| x | interrupt.bits());
+        }
+    }
+
+    pub fn disable_interrupt(&self, interrupt: Interrupt) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC0.get()).timsk0.modify(|x| x - interrupt.bits());
+        }
+    }
+}
+
+/// The number of compare matches per tick and the number remaining
+/// until the next tick.  An 8-bit counter cannot divide the clock down
+/// to typical tick rates, so the Ticker counts compare matches in software.
+static mut POSTSCALE: u8 = 1;
+static mut POSTSCALE_REMAINING: u8 = 1;
+static mut TICK: Option<fn()> = None;
+
+fn ticker_compare_a() {
+    unsafe {
+        let remaining = read_volatile(&POSTSCALE_REMAINING) - 1;
+        if remaining == 0 {
+            write_volatile(&mut POSTSCALE_REMAINING, read_volatile(&POSTSCALE));
+            if let Some(tick) = read_volatile(&TICK) {
+                tick();
+            }
+        } else {
+            write_volatile(&mut POSTSCALE_REMAINING, remaining);
+        }
+    }
+}
+
+/// Drives the event loop from the Timer0 compare A interrupt in CTC mode.
+pub struct Ticker {
+    timer: Option<Handle>,
+}
+
+impl Ticker {
+    pub fn new() -> Self {
+        Self { timer: None }
+    }
+}
+
+impl TickSource for Ticker {
+    fn start(&mut self, hz: u16, tick: fn()) {
+        let count = clock::frequency() / (hz as u32 * 1024);
+        let postscale = (count + 255) / 256;
+        let postscale = if postscale == 0 { 1 } else { postscale };
+        let compare = count / postscale;
+        let compare = if compare == 0 { 0 } else { compare - 1 };
+
+        interrupt_free(|_cs| unsafe {
+            write_volatile(&mut POSTSCALE, postscale as u8);
+            write_volatile(&mut POSTSCALE_REMAINING, postscale as u8);
+            write_volatile(&mut TICK, Some(tick));
+        });
+        set_interrupt_handler(Interrupt::CompareA, Some(ticker_compare_a));
+        self.timer = Some(
+            Timer::new()
+                .waveform_generation_mode(WaveformGenerationMode::ClearOnTimerMatchOutputCompare)
+                .clock_source(ClockSource::Prescale1024)
+                .output_compare(Channel::A, compare as u8)
+                .interrupt(Interrupt::CompareA)
+                .configure(),
+        );
+    }
+
+    fn stop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer.disable_interrupt(Interrupt::CompareA);
+        }
+        set_interrupt_handler(Interrupt::CompareA, None);
+        interrupt_free(|_cs| unsafe {
+            write_volatile(&mut TICK, None);
+        });
+    }
+}
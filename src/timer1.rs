@@ -1,6 +1,11 @@
-use mcu::{TC1, Tc1Tccr1aFlags, Tc1Tccr1bFlags, Tc1Tccr1cFlags, Tc1Timsk1Flags};
-use mutex::interrupt_free;
+use mcu::{TC1, Tc1Tccr1aFlags, Tc1Tccr1bFlags, Tc1Tccr1cFlags, Tc1Tifr1Flags, Tc1Timsk1Flags};
+use mutex::{interrupt_free, CriticalSection};
+use power::{self, PowerHandle};
+use core::ptr::{read_volatile, write_volatile};
+use clock;
+use eventloop::TickSource;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ClockSource {
     None,
     Prescale1,
@@ -14,7 +19,7 @@ pub enum ClockSource {
 
 impl ClockSource {
     #[inline]
-    fn bits(&self) -> Tc1Tccr1bFlags {
+    pub(crate) fn bits(&self) -> Tc1Tccr1bFlags {
         use self::ClockSource::*;
         match *self {
             None => Tc1Tccr1bFlags::CLK_SEL_3BIT_EXT_NO_CLOCK_SOURCE_STOPPED,
@@ -27,8 +32,43 @@ impl ClockSource {
             ExternalRising => Tc1Tccr1bFlags::CLK_SEL_3BIT_EXT_RUNNING_EXTCLK_TX_RISING_EDGE,
         }
     }
+
+    /// Returns the amount by which the CPU clock is divided, or None
+    /// if the timer is stopped or clocked from the T1 pin.
+    pub fn divisor(&self) -> Option<u32> {
+        use self::ClockSource::*;
+        match *self {
+            Prescale1 => Some(1),
+            Prescale8 => Some(8),
+            Prescale64 => Some(64),
+            Prescale256 => Some(256),
+            Prescale1024 => Some(1024),
+            None | ExternalFalling | ExternalRising => Option::None,
+        }
+    }
+
+    /// Returns the smallest prescaler whose CTC compare value for `hz`
+    /// interrupts per second at the current clock::frequency() fits in
+    /// 16 bits, along with that compare value.  Rates that the clock
+    /// is too slow to reach are clamped to a compare value of zero
+    /// rather than wrapping around.
+    pub(crate) fn for_tick_rate(hz: u16) -> (ClockSource, u16) {
+        use self::ClockSource::*;
+        let frequency = clock::frequency();
+        let hz = if hz == 0 { 1 } else { hz as u32 };
+        let mut result = (Prescale1024, 0xffff);
+        for source in [Prescale1024, Prescale256, Prescale64, Prescale8, Prescale1].iter() {
+            let count = frequency / (hz * source.divisor().unwrap());
+            if count > 0x1_0000 {
+                break;
+            }
+            result = (*source, if count == 0 { 0 } else { (count - 1) as u16 });
+        }
+        result
+    }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum WaveformGenerationMode {
     Normal,
     PwmPhaseCorrect8Bit,
@@ -55,7 +95,7 @@ const WGM13: Tc1Tccr1bFlags = Tc1Tccr1bFlags::from_bits(1 << 4);
 
 impl WaveformGenerationMode {
     #[inline]
-    fn bits(&self) -> (Tc1Tccr1bFlags, Tc1Tccr1aFlags) {
+    pub(crate) fn bits(&self) -> (Tc1Tccr1bFlags, Tc1Tccr1aFlags) {
         use self::WaveformGenerationMode::*;
         match *self {
             Normal => (Tc1Tccr1bFlags::empty(), Tc1Tccr1aFlags::empty()),
@@ -76,22 +116,246 @@ impl WaveformGenerationMode {
             FastPwmOutputCompare => (WGM13 | WGM12, WGM11 | WGM10),
         }
     }
+
+    /// Returns the fixed TOP value for the modes that have one
+    #[inline]
+    pub(crate) fn fixed_top(&self) -> Option<u16> {
+        use self::WaveformGenerationMode::*;
+        match *self {
+            Normal => Some(0xffff),
+            PwmPhaseCorrect8Bit | FastPwm8Bit => Some(0x00ff),
+            PwmPhaseCorrect9Bit | FastPwm9Bit => Some(0x01ff),
+            PwmPhaseCorrect10Bit | FastPwm10Bit => Some(0x03ff),
+            _ => None,
+        }
+    }
+
+    /// Returns true if TOP is defined by the ICR1 register
+    #[inline]
+    pub(crate) fn top_is_input_capture(&self) -> bool {
+        use self::WaveformGenerationMode::*;
+        match *self {
+            PwmPhaseAndFrequencyCorrectInputCapture
+            | PwmPhaseCorrectInputCapture
+            | ClearOnTimerMatchInputCapture
+            | FastPwmInputCapture => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if TOP is defined by the OCR1A register
+    #[inline]
+    pub(crate) fn top_is_output_compare(&self) -> bool {
+        use self::WaveformGenerationMode::*;
+        match *self {
+            ClearOnTimerMatchOutputCompare
+            | PwmPhaseAndFrequencyCorrectOutputCompare
+            | PwmPhaseCorrectOutputCompare
+            | FastPwmOutputCompare => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true for the fast and phase correct PWM modes
+    pub fn is_pwm(&self) -> bool {
+        use self::WaveformGenerationMode::*;
+        match *self {
+            Normal | ClearOnTimerMatchOutputCompare | ClearOnTimerMatchInputCapture => false,
+            _ => true,
+        }
+    }
+}
+
+/// Selects one of the three output compare units
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channel {
+    A,
+    B,
+    C,
+}
+
+impl Channel {
+    #[inline]
+    pub(crate) fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// The COM1x bits for this channel are 2 bits wide and start
+    /// at bit 6 for A, 4 for B and 2 for C.
+    #[inline]
+    pub(crate) fn com_shift(&self) -> u8 {
+        6 - 2 * (*self as u8)
+    }
+
+    #[inline]
+    pub(crate) fn force_bits(&self) -> Tc1Tccr1cFlags {
+        match *self {
+            Channel::A => Tc1Tccr1cFlags::FOC1A,
+            Channel::B => Tc1Tccr1cFlags::FOC1B,
+            Channel::C => Tc1Tccr1cFlags::FOC1C,
+        }
+    }
+}
+
+/// Controls what happens to the OC1x pin on a compare match.
+/// The precise meaning of Clear and Set depends on the waveform
+/// generation mode; in the PWM modes Clear is non-inverting and
+/// Set is inverting output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompareOutputMode {
+    Disconnected,
+    Toggle,
+    Clear,
+    Set,
+}
+
+impl CompareOutputMode {
+    #[inline]
+    pub(crate) fn bits(&self, channel: Channel) -> Tc1Tccr1aFlags {
+        use self::CompareOutputMode::*;
+        let mode = match *self {
+            Disconnected => 0,
+            Toggle => 1,
+            Clear => 2,
+            Set => 3,
+        };
+        Tc1Tccr1aFlags::from_bits(mode << channel.com_shift())
+    }
+
+    #[inline]
+    pub(crate) fn mask(channel: Channel) -> Tc1Tccr1aFlags {
+        CompareOutputMode::Set.bits(channel)
+    }
+}
+
+/// Which edge on the ICP1 pin triggers an input capture
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CaptureEdge {
+    Falling,
+    Rising,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    CompareA,
+    CompareB,
+    CompareC,
+    Overflow,
+    InputCapture,
+}
+
+const NUM_INTERRUPTS: usize = 5;
+
+impl Interrupt {
+    #[inline]
+    pub(crate) fn bits(&self) -> Tc1Timsk1Flags {
+        use self::Interrupt::*;
+        match *self {
+            CompareA => Tc1Timsk1Flags::OCIE1A,
+            CompareB => Tc1Timsk1Flags::OCIE1B,
+            CompareC => Tc1Timsk1Flags::OCIE1C,
+            Overflow => Tc1Timsk1Flags::TOIE1,
+            InputCapture => Tc1Timsk1Flags::ICIE1,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// The functions to call from the timer1 interrupt vectors
+static mut HANDLERS: [Option<fn()>; NUM_INTERRUPTS] = [None; NUM_INTERRUPTS];
+
+/// Set while the Ticker, a Pwm, a Capture or a Timer1CountDown
+/// owns Timer1
+static mut CLAIMED: bool = false;
+
+/// Take ownership of Timer1 on behalf of one of the drivers that
+/// configure it.  Returns false if another one already owns it.
+pub(crate) fn claim() -> bool {
+    let _cs = CriticalSection::new();
+    unsafe {
+        if read_volatile(&CLAIMED) {
+            return false;
+        }
+        write_volatile(&mut CLAIMED, true);
+    }
+    true
+}
+
+/// Give up ownership taken by claim()
+pub(crate) fn release() {
+    unsafe {
+        write_volatile(&mut CLAIMED, false);
+    }
+}
+
+/// Register a function to be called from the interrupt vector.
+/// The interrupt itself is enabled via Timer::interrupt() or
+/// Handle::enable_interrupt().
+pub fn set_interrupt_handler(interrupt: Interrupt, handler: Option<fn()>) {
+    interrupt_free(|_cs| unsafe {
+        write_volatile(&mut HANDLERS[interrupt.index()], handler);
+    });
+}
+
+#[inline]
+fn dispatch(interrupt: Interrupt) {
+    unsafe {
+        if let Some(handler) = read_volatile(&HANDLERS[interrupt.index()]) {
+            handler();
+        }
+    }
+}
+
+fn timer1_compare_a() {
+    dispatch(Interrupt::CompareA);
+}
+
+fn timer1_compare_b() {
+    dispatch(Interrupt::CompareB);
+}
+
+fn timer1_compare_c() {
+    dispatch(Interrupt::CompareC);
+}
+
+fn timer1_overflow() {
+    dispatch(Interrupt::Overflow);
+}
+
+fn timer1_capture() {
+    dispatch(Interrupt::InputCapture);
 }
 
+irq_handler!(TIMER1_COMPA, timer1_compare_a);
+irq_handler!(TIMER1_COMPB, timer1_compare_b);
+irq_handler!(TIMER1_COMPC, timer1_compare_c);
+irq_handler!(TIMER1_OVF, timer1_overflow);
+irq_handler!(TIMER1_CAPT, timer1_capture);
+
 pub struct Timer {
+    wgm: WaveformGenerationMode,
     a: Tc1Tccr1aFlags,
     b: Tc1Tccr1bFlags,
     c: Tc1Tccr1cFlags,
-    compare: Option<u16>,
+    compare: [Option<u16>; 3],
+    input_capture: Option<u16>,
+    interrupts: Tc1Timsk1Flags,
 }
 
 impl Timer {
     pub fn new() -> Self {
         Self {
+            wgm: WaveformGenerationMode::Normal,
             a: Tc1Tccr1aFlags::empty(),
             b: Tc1Tccr1bFlags::empty(),
             c: Tc1Tccr1cFlags::empty(),
-            compare: None,
+            compare: [None; 3],
+            input_capture: None,
+            interrupts: Tc1Timsk1Flags::empty(),
         }
     }
 
@@ -100,21 +364,96 @@ impl Timer {
 
         self.a |= a;
         self.b |= b;
+        self.wgm = wgm;
 
         self
     }
 
+    /// Returns the most recently selected waveform generation mode
+    pub fn mode(&self) -> WaveformGenerationMode {
+        self.wgm
+    }
+
+    /// Returns the value of TOP for the configured waveform generation
+    /// mode along with the channel whose compare register is used to
+    /// define it, if any.  Returns None if TOP is defined by a register
+    /// that has not been set.
+    pub fn top(&self) -> Option<(u16, Option<Channel>)> {
+        if let Some(top) = self.wgm.fixed_top() {
+            Some((top, None))
+        } else if self.wgm.top_is_input_capture() {
+            self.input_capture.map(|top| (top, None))
+        } else if self.wgm.top_is_output_compare() {
+            self.compare[Channel::A.index()].map(|top| (top, Some(Channel::A)))
+        } else {
+            None
+        }
+    }
+
     pub fn clock_source(mut self, src: ClockSource) -> Self {
         self.b |= src.bits();
         self
     }
 
-    pub fn output_compare_1(mut self, value: u16) -> Self {
-        self.compare = Some(value);
+    /// Set the OCR1A compare value and enable the compare A interrupt
+    pub fn output_compare_1(self, value: u16) -> Self {
+        self.output_compare(Channel::A, value)
+            .interrupt(Interrupt::CompareA)
+    }
+
+    /// Set the compare value for a channel
+    pub fn output_compare(mut self, channel: Channel, value: u16) -> Self {
+        self.compare[channel.index()] = Some(value);
+        self
+    }
+
+    /// Set the behavior of the OC1x pin for a channel.  The pin must
+    /// also be configured as an output for the waveform to be visible.
+    pub fn compare_output_mode(mut self, channel: Channel, mode: CompareOutputMode) -> Self {
+        self.a -= CompareOutputMode::mask(channel);
+        self.a |= mode.bits(channel);
+        self
+    }
+
+    /// Set the ICR1 value.  This defines TOP in the waveform generation
+    /// modes that are based on input capture.
+    pub fn input_capture(mut self, value: u16) -> Self {
+        self.input_capture = Some(value);
+        self
+    }
+
+    /// Select the ICP1 edge that triggers an input capture
+    pub fn input_capture_edge(mut self, edge: CaptureEdge) -> Self {
+        match edge {
+            CaptureEdge::Falling => self.b -= Tc1Tccr1bFlags::ICES1,
+            CaptureEdge::Rising => self.b |= Tc1Tccr1bFlags::ICES1,
+        }
+        self
+    }
+
+    /// Enable the input capture noise canceler, which filters ICP1
+    /// over 4 samples at the cost of 4 clock cycles of latency.
+    pub fn input_capture_noise_canceler(mut self, enable: bool) -> Self {
+        if enable {
+            self.b |= Tc1Tccr1bFlags::ICNC1;
+        } else {
+            self.b -= Tc1Tccr1bFlags::ICNC1;
+        }
+        self
+    }
+
+    /// Enable an interrupt.  Use set_interrupt_handler() to register
+    /// the function that will be called from the interrupt.
+    pub fn interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.interrupts |= interrupt.bits();
         self
     }
 
-    pub fn configure(self) {
+    /// Apply the configuration to the hardware.  The timer's clock
+    /// remains enabled until the returned Handle is dropped.
+    /// Interrupts that were enabled prior to this call are left enabled.
+    pub fn configure(self) -> Handle {
+        let power = power::acquire(power::Peripheral::Timer1);
         unsafe {
             interrupt_free(|_cs| {
                 let tc1 = &(*TC1.get());
@@ -123,11 +462,186 @@ impl Timer {
                 tc1.tccr1c.write(self.c);
                 tc1.tcnt1.write(0);
 
-                if let Some(compare) = self.compare {
+                if let Some(compare) = self.compare[Channel::A.index()] {
                     tc1.ocr1a.write(compare);
-                    tc1.timsk1.write(Tc1Timsk1Flags::OCIE1A);
                 }
+                if let Some(compare) = self.compare[Channel::B.index()] {
+                    tc1.ocr1b.write(compare);
+                }
+                if let Some(compare) = self.compare[Channel::C.index()] {
+                    tc1.ocr1c.write(compare);
+                }
+                if let Some(capture) = self.input_capture {
+                    tc1.icr1.write(capture);
+                }
+
+                tc1.timsk1.modify(|x| x | self.interrupts);
             });
         }
+        Handle { _power: power }
+    }
+}
+
+/// Represents a configured Timer1.  Provides access to the
+/// counter and compare registers without reconfiguring the timer.
+#[must_use]
+pub struct Handle {
+    _power: PowerHandle,
+}
+
+impl Handle {
+    /// Returns the current value of TCNT1
+    pub fn counter(&self) -> u16 {
+        // 16 bit access goes via the shared TEMP register
+        // and must not be interrupted.
+        let _cs = CriticalSection::new();
+        unsafe { (*TC1.get()).tcnt1.read() }
+    }
+
+    pub fn set_counter(&self, value: u16) {
+        let _cs = CriticalSection::new();
+        unsafe { (*TC1.get()).tcnt1.write(value) }
+    }
+
+    /// Returns the counter value latched by the most recent input capture
+    pub fn input_capture(&self) -> u16 {
+        let _cs = CriticalSection::new();
+        unsafe { (*TC1.get()).icr1.read() }
+    }
+
+    pub fn set_input_capture(&self, value: u16) {
+        let _cs = CriticalSection::new();
+        unsafe { (*TC1.get()).icr1.write(value) }
+    }
+
+    /// Returns the compare value for a channel
+    pub fn output_compare(&self, channel: Channel) -> u16 {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let tc1 = &(*TC1.get());
+            match channel {
+                Channel::A => tc1.ocr1a.read(),
+                Channel::B => tc1.ocr1b.read(),
+                Channel::C => tc1.ocr1c.read(),
+            }
+        }
+    }
+
+    /// Update the compare value for a channel.  In the PWM modes
+    /// the hardware double buffers this until the counter reaches TOP
+    /// or BOTTOM.
+    pub fn set_output_compare(&self, channel: Channel, value: u16) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let tc1 = &(*TC1.get());
+            match channel {
+                Channel::A => tc1.ocr1a.write(value),
+                Channel::B => tc1.ocr1b.write(value),
+                Channel::C => tc1.ocr1c.write(value),
+            }
+        }
+    }
+
+    pub fn set_compare_output_mode(&self, channel: Channel, mode: CompareOutputMode) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC1.get())
+                .tccr1a
+                .modify(|x| (x - CompareOutputMode::mask(channel)) | mode.bits(channel));
+        }
+    }
+
+    /// Select the ICP1 edge that triggers an input capture
+    pub fn set_input_capture_edge(&self, edge: CaptureEdge) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC1.get()).tccr1b.modify(|x| match edge {
+                CaptureEdge::Falling => x - Tc1Tccr1bFlags::ICES1,
+                CaptureEdge::Rising => x | Tc1Tccr1bFlags::ICES1,
+            });
+        }
+    }
+
+    /// Strobe a compare match for a channel without setting the
+    /// interrupt flag or resetting the counter.  Only valid in
+    /// the non-PWM modes.
+    pub fn force_output_compare(&self, channel: Channel) {
+        unsafe {
+            (*TC1.get()).tccr1c.write(channel.force_bits());
+        }
+    }
+
+    pub fn enable_interrupt(&self, interrupt: Interrupt) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC1.get()).timsk1.modify(|x| x | interrupt.bits());
+        }
+    }
+
+    pub fn disable_interrupt(&self, interrupt: Interrupt) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC1.get()).timsk1.modify(|x| x - interrupt.bits());
+        }
+    }
+
+    /// Returns true if the condition for an interrupt has occurred,
+    /// clearing the flag in the process.  This allows polling for
+    /// events without enabling the interrupt itself.
+    pub fn take_interrupt_flag(&self, interrupt: Interrupt) -> bool {
+        // TIFR1 has the same layout as TIMSK1
+        let flag = Tc1Tifr1Flags::from_bits(interrupt.bits().bits());
+        unsafe {
+            let tc1 = &(*TC1.get());
+            if (tc1.tifr1.read() & flag) == flag {
+                // Flags are cleared by writing a one
+                tc1.tifr1.write(flag);
+                true
+            } else {
+                false
+            }
+        }
+    }
+}
+
+/// Drives the event loop from the Timer1 compare A interrupt in CTC mode.
+pub struct Ticker {
+    timer: Option<Handle>,
+}
+
+impl Ticker {
+    /// Returns None if something else already owns Timer1
+    pub fn new() -> Option<Self> {
+        if !claim() {
+            return None;
+        }
+        Some(Self { timer: None })
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        release();
+    }
+}
+
+impl TickSource for Ticker {
+    fn start(&mut self, hz: u16, tick: fn()) {
+        let (source, compare) = ClockSource::for_tick_rate(hz);
+        set_interrupt_handler(Interrupt::CompareA, Some(tick));
+        self.timer = Some(
+            Timer::new()
+                .waveform_generation_mode(WaveformGenerationMode::ClearOnTimerMatchOutputCompare)
+                .clock_source(source)
+                .output_compare_1(compare)
+                .configure(),
+        );
+    }
+
+    fn stop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer.disable_interrupt(Interrupt::CompareA);
+        }
+        set_interrupt_handler(Interrupt::CompareA, None);
     }
 }
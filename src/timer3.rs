@@ -0,0 +1,360 @@
+//! Timer3 is a 16-bit timer with the same register layout as Timer1.
+//! Only the OC3A (PC6) output and the ICP3 (PC7) input are connected
+//! to pins on the atmega32u4, but all three compare units can be used
+//! to generate interrupts.
+//! The enums from the timer1 module are reused here; their bit
+//! definitions are converted to the Timer3 register types.
+use mcu::{TC3, Tc1Tccr1aFlags, Tc1Tccr1bFlags, Tc1Tccr1cFlags, Tc1Timsk1Flags, Tc3Tccr3aFlags,
+          Tc3Tccr3bFlags, Tc3Tccr3cFlags, Tc3Timsk3Flags};
+use mutex::{interrupt_free, CriticalSection};
+use power::{self, PowerHandle};
+use core::ptr::{read_volatile, write_volatile};
+use eventloop::TickSource;
+pub use timer1::{CaptureEdge, Channel, ClockSource, CompareOutputMode, Interrupt,
+                 WaveformGenerationMode};
+
+const NUM_INTERRUPTS: usize = 5;
+
+#[inline]
+fn tccr3a(flags: Tc1Tccr1aFlags) -> Tc3Tccr3aFlags {
+    Tc3Tccr3aFlags::from_bits(flags.bits())
+}
+
+#[inline]
+fn tccr3b(flags: Tc1Tccr1bFlags) -> Tc3Tccr3bFlags {
+    Tc3Tccr3bFlags::from_bits(flags.bits())
+}
+
+#[inline]
+fn tccr3c(flags: Tc1Tccr1cFlags) -> Tc3Tccr3cFlags {
+    Tc3Tccr3cFlags::from_bits(flags.bits())
+}
+
+#[inline]
+fn timsk3(flags: Tc1Timsk1Flags) -> Tc3Timsk3Flags {
+    Tc3Timsk3Flags::from_bits(flags.bits())
+}
+
+/// The functions to call from the timer3 interrupt vectors
+static mut HANDLERS: [Option<fn()>; NUM_INTERRUPTS] = [None; NUM_INTERRUPTS];
+
+/// Register a function to be called from the interrupt vector.
+/// The interrupt itself is enabled via Timer::interrupt() or
+/// Handle::enable_interrupt().
+pub fn set_interrupt_handler(interrupt: Interrupt, handler: Option<fn()>) {
+    interrupt_free(|_cs| unsafe {
+        write_volatile(&mut HANDLERS[interrupt.index()], handler);
+    });
+}
+
+#[inline]
+fn dispatch(interrupt: Interrupt) {
+    unsafe {
+        if let Some(handler) = read_volatile(&HANDLERS[interrupt.index()]) {
+            handler();
+        }
+    }
+}
+
+fn timer3_compare_a() {
+    dispatch(Interrupt::CompareA);
+}
+
+fn timer3_compare_b() {
+    dispatch(Interrupt::CompareB);
+}
+
+fn timer3_compare_c() {
+    dispatch(Interrupt::CompareC);
+}
+
+fn timer3_overflow() {
+    dispatch(Interrupt::Overflow);
+}
+
+fn timer3_capture() {
+    dispatch(Interrupt::InputCapture);
+}
+
+irq_handler!(TIMER3_COMPA, timer3_compare_a);
+irq_handler!(TIMER3_COMPB, timer3_compare_b);
+irq_handler!(TIMER3_COMPC, timer3_compare_c);
+irq_handler!(TIMER3_OVF, timer3_overflow);
+irq_handler!(TIMER3_CAPT, timer3_capture);
+
+pub struct Timer {
+    wgm: WaveformGenerationMode,
+    a: Tc3Tccr3aFlags,
+    b: Tc3Tccr3bFlags,
+    c: Tc3Tccr3cFlags,
+    compare: [Option<u16>; 3],
+    input_capture: Option<u16>,
+    interrupts: Tc3Timsk3Flags,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            wgm: WaveformGenerationMode::Normal,
+            a: Tc3Tccr3aFlags::empty(),
+            b: Tc3Tccr3bFlags::empty(),
+            c: Tc3Tccr3cFlags::empty(),
+            compare: [None; 3],
+            input_capture: None,
+            interrupts: Tc3Timsk3Flags::empty(),
+        }
+    }
+
+    pub fn waveform_generation_mode(mut self, wgm: WaveformGenerationMode) -> Self {
+        let (b, a) = wgm.bits();
+
+        self.a |= tccr3a(a);
+        self.b |= tccr3b(b);
+        self.wgm = wgm;
+
+        self
+    }
+
+    /// Returns the most recently selected waveform generation mode
+    pub fn mode(&self) -> WaveformGenerationMode {
+        self.wgm
+    }
+
+    /// Returns the value of TOP for the configured waveform generation
+    /// mode along with the channel whose compare register is used to
+    /// define it, if any.  Returns None if TOP is defined by a register
+    /// that has not been set.
+    pub fn top(&self) -> Option<(u16, Option<Channel>)> {
+        if let Some(top) = self.wgm.fixed_top() {
+            Some((top, None))
+        } else if self.wgm.top_is_input_capture() {
+            self.input_capture.map(|top| (top, None))
+        } else if self.wgm.top_is_output_compare() {
+            self.compare[Channel::A.index()].map(|top| (top, Some(Channel::A)))
+        } else {
+            None
+        }
+    }
+
+    pub fn clock_source(mut self, src: ClockSource) -> Self {
+        self.b |= tccr3b(src.bits());
+        self
+    }
+
+    /// Set the compare value for a channel
+    pub fn output_compare(mut self, channel: Channel, value: u16) -> Self {
+        self.compare[channel.index()] = Some(value);
+        self
+    }
+
+    /// Set the behavior of the OC3x pin for a channel.  The pin must
+    /// also be configured as an output for the waveform to be visible.
+    pub fn compare_output_mode(mut self, channel: Channel, mode: CompareOutputMode) -> Self {
+        self.a -= tccr3a(CompareOutputMode::mask(channel));
+        self.a |= tccr3a(mode.bits(channel));
+        self
+    }
+
+    /// Set the ICR3 value.  This defines TOP in the waveform generation
+    /// modes that are based on input capture.
+    pub fn input_capture(mut self, value: u16) -> Self {
+        self.input_capture = Some(value);
+        self
+    }
+
+    /// Select the ICP3 edge that triggers an input capture
+    pub fn input_capture_edge(mut self, edge: CaptureEdge) -> Self {
+        match edge {
+            CaptureEdge::Falling => self.b -= Tc3Tccr3bFlags::ICES3,
+            CaptureEdge::Rising => self.b |= Tc3Tccr3bFlags::ICES3,
+        }
+        self
+    }
+
+    /// Enable the input capture noise canceler, which filters ICP3
+    /// over 4 samples at the cost of 4 clock cycles of latency.
+    pub fn input_capture_noise_canceler(mut self, enable: bool) -> Self {
+        if enable {
+            self.b |= Tc3Tccr3bFlags::ICNC3;
+        } else {
+            self.b -= Tc3Tccr3bFlags::ICNC3;
+        }
+        self
+    }
+
+    /// Enable an interrupt.  Use set_interrupt_handler() to register
+    /// the function that will be called from the interrupt.
+    pub fn interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.interrupts |= timsk3(interrupt.bits());
+        self
+    }
+
+    /// Apply the configuration to the hardware.  The timer's clock
+    /// remains enabled until the returned Handle is dropped.
+    /// Interrupts that were enabled prior to this call are left enabled.
+    pub fn configure(self) -> Handle {
+        let power = power::acquire(power::Peripheral::Timer3);
+        unsafe {
+            interrupt_free(|_cs| {
+                let tc3 = &(*TC3.get());
+                tc3.tccr3a.write(self.a);
+                tc3.tccr3b.write(self.b);
+                tc3.tccr3c.write(self.c);
+                tc3.tcnt3.write(0);
+
+                if let Some(compare) = self.compare[Channel::A.index()] {
+                    tc3.ocr3a.write(compare);
+                }
+                if let Some(compare) = self.compare[Channel::B.index()] {
+                    tc3.ocr3b.write(compare);
+                }
+                if let Some(compare) = self.compare[Channel::C.index()] {
+                    tc3.ocr3c.write(compare);
+                }
+                if let Some(capture) = self.input_capture {
+                    tc3.icr3.write(capture);
+                }
+
+                tc3.timsk3.modify(|x| x | self.interrupts);
+            });
+        }
+        Handle { _power: power }
+    }
+}
+
+/// Represents a configured Timer3.  Provides access to the
+/// counter and compare registers without reconfiguring the timer.
+#[must_use]
+pub struct Handle {
+    _power: PowerHandle,
+}
+
+impl Handle {
+    /// Returns the current value of TCNT3
+    pub fn counter(&self) -> u16 {
+        // 16 bit access goes via the shared TEMP register
+        // and must not be interrupted.
+        let _cs = CriticalSection::new();
+        unsafe { (*TC3.get()).tcnt3.read() }
+    }
+
+    pub fn set_counter(&self, value: u16) {
+        let _cs = CriticalSection::new();
+        unsafe { (*TC3.get()).tcnt3.write(value) }
+    }
+
+    /// Returns the counter value latched by the most recent input capture
+    pub fn input_capture(&self) -> u16 {
+        let _cs = CriticalSection::new();
+        unsafe { (*TC3.get()).icr3.read() }
+    }
+
+    pub fn set_input_capture(&self, value: u16) {
+        let _cs = CriticalSection::new();
+        unsafe { (*TC3.get()).icr3.write(value) }
+    }
+
+    /// Returns the compare value for a channel
+    pub fn output_compare(&self, channel: Channel) -> u16 {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let tc3 = &(*TC3.get());
+            match channel {
+                Channel::A => tc3.ocr3a.read(),
+                Channel::B => tc3.ocr3b.read(),
+                Channel::C => tc3.ocr3c.read(),
+            }
+        }
+    }
+
+    /// Update the compare value for a channel.  In the PWM modes
+    /// the hardware double buffers this until the counter reaches TOP
+    /// or BOTTOM.
+    pub fn set_output_compare(&self, channel: Channel, value: u16) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let tc3 = &(*TC3.get());
+            match channel {
+                Channel::A => tc3.ocr3a.write(value),
+                Channel::B => tc3.ocr3b.write(value),
+                Channel::C => tc3.ocr3c.write(value),
+            }
+        }
+    }
+
+    pub fn set_compare_output_mode(&self, channel: Channel, mode: CompareOutputMode) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC3.get())
+                .tccr3a
+                .modify(|x| (x - tccr3a(CompareOutputMode::mask(channel))) | tccr3a(mode.bits(channel)));
+        }
+    }
+
+    /// Select the ICP3 edge that triggers an input capture
+    pub fn set_input_capture_edge(&self, edge: CaptureEdge) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC3.get()).tccr3b.modify(|x| match edge {
+                CaptureEdge::Falling => x - Tc3Tccr3bFlags::ICES3,
+                CaptureEdge::Rising => x | Tc3Tccr3bFlags::ICES3,
+            });
+        }
+    }
+
+    /// Strobe a compare match for a channel without setting the
+    /// interrupt flag or resetting the counter.  Only valid in
+    /// the non-PWM modes.
+    pub fn force_output_compare(&self, channel: Channel) {
+        unsafe {
+            (*TC3.get()).tccr3c.write(tccr3c(channel.force_bits()));
+        }
+    }
+
+    pub fn enable_interrupt(&self, interrupt: Interrupt) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC3.get()).timsk3.modify(|x| x | timsk3(interrupt.bits()));
+        }
+    }
+
+    pub fn disable_interrupt(&self, interrupt: Interrupt) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC3.get()).timsk3.modify(|x| x - timsk3(interrupt.bits()));
+        }
+    }
+}
+
+/// Drives the event loop from the Timer3 compare A interrupt in CTC mode.
+pub struct Ticker {
+    timer: Option<Handle>,
+}
+
+impl Ticker {
+    pub fn new() -> Self {
+        Self { timer: None }
+    }
+}
+
+impl TickSource for Ticker {
+    fn start(&mut self, hz: u16, tick: fn()) {
+        let (source, compare) = ClockSource::for_tick_rate(hz);
+        set_interrupt_handler(Interrupt::CompareA, Some(tick));
+        self.timer = Some(
+            Timer::new()
+                .waveform_generation_mode(WaveformGenerationMode::ClearOnTimerMatchOutputCompare)
+                .clock_source(source)
+                .output_compare(Channel::A, compare)
+                .interrupt(Interrupt::CompareA)
+                .configure(),
+        );
+    }
+
+    fn stop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer.disable_interrupt(Interrupt::CompareA);
+        }
+        set_interrupt_handler(Interrupt::CompareA, None);
+    }
+}
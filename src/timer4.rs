@@ -0,0 +1,506 @@
+//! Timer4 is a 10-bit high speed timer that can be clocked from the PLL.
+//! TOP is defined by OCR4C.  Its three compare units drive OC4A (PC7),
+//! OC4B (PB6) and OC4D (PD7), and in the PWM modes the complementary
+//! outputs ~OC4A (PC6), ~OC4B (PB5) and ~OC4D (PD6) can be enabled
+//! with programmable dead time between them.
+//! The 10-bit registers are accessed via the shared TC4H register
+//! for the upper two bits.
+use mcu::{TC4, Tc4Dt4Flags, Tc4Tccr4aFlags, Tc4Tccr4bFlags, Tc4Tccr4cFlags, Tc4Tccr4dFlags,
+          Tc4Timsk4Flags, PllPllfrqFlags, PLL};
+use mutex::{interrupt_free, CriticalSection};
+use power::{self, PowerHandle};
+use pll::{self, PllHandle};
+use core::ptr::{read_volatile, write_volatile};
+use volatile_register::RW;
+use timer1;
+pub use timer1::CompareOutputMode;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    None,
+    Prescale1,
+    Prescale2,
+    Prescale4,
+    Prescale8,
+    Prescale16,
+    Prescale32,
+    Prescale64,
+    Prescale128,
+    Prescale256,
+    Prescale512,
+    Prescale1024,
+    Prescale2048,
+    Prescale4096,
+    Prescale8192,
+    Prescale16384,
+}
+
+impl ClockSource {
+    #[inline]
+    fn bits(&self) -> Tc4Tccr4bFlags {
+        // The CS43:0 value is the same as the discriminant
+        Tc4Tccr4bFlags::from_bits(*self as u8)
+    }
+}
+
+/// Selects the clock that feeds the Timer4 prescaler
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClockInput {
+    /// The system clock
+    System,
+    /// The 48MHz PLL output
+    Pll,
+    /// The PLL output divided by 1.5 (32MHz)
+    PllDiv1_5,
+    /// The PLL output divided by 2 (24MHz)
+    PllDiv2,
+}
+
+const PLLTM_MASK: PllPllfrqFlags = PllPllfrqFlags::from_bits(0b11 << 4);
+
+impl ClockInput {
+    #[inline]
+    fn bits(&self) -> PllPllfrqFlags {
+        use self::ClockInput::*;
+        PllPllfrqFlags::from_bits(
+            match *self {
+                System => 0b00,
+                Pll => 0b01,
+                PllDiv1_5 => 0b10,
+                PllDiv2 => 0b11,
+            } << 4,
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WaveformGenerationMode {
+    Normal,
+    FastPwm,
+    PwmPhaseAndFrequencyCorrect,
+    Pwm6SingleSlope,
+    Pwm6DualSlope,
+}
+
+const WGM40: Tc4Tccr4dFlags = Tc4Tccr4dFlags::from_bits(1 << 0);
+const WGM41: Tc4Tccr4dFlags = Tc4Tccr4dFlags::from_bits(1 << 1);
+
+const PWM4B: Tc4Tccr4aFlags = Tc4Tccr4aFlags::from_bits(1 << 0);
+const PWM4A: Tc4Tccr4aFlags = Tc4Tccr4aFlags::from_bits(1 << 1);
+const FOC4B: Tc4Tccr4aFlags = Tc4Tccr4aFlags::from_bits(1 << 2);
+const FOC4A: Tc4Tccr4aFlags = Tc4Tccr4aFlags::from_bits(1 << 3);
+
+const PWM4D: Tc4Tccr4cFlags = Tc4Tccr4cFlags::from_bits(1 << 0);
+const FOC4D: Tc4Tccr4cFlags = Tc4Tccr4cFlags::from_bits(1 << 1);
+
+const DTPS4_MASK: Tc4Tccr4bFlags = Tc4Tccr4bFlags::from_bits(0b11 << 4);
+
+impl WaveformGenerationMode {
+    #[inline]
+    fn bits(&self) -> Tc4Tccr4dFlags {
+        use self::WaveformGenerationMode::*;
+        match *self {
+            Normal | FastPwm => Tc4Tccr4dFlags::empty(),
+            PwmPhaseAndFrequencyCorrect => WGM40,
+            Pwm6SingleSlope => WGM41,
+            Pwm6DualSlope => WGM41 | WGM40,
+        }
+    }
+
+    /// Returns true for the PWM modes
+    pub fn is_pwm(&self) -> bool {
+        *self != WaveformGenerationMode::Normal
+    }
+}
+
+/// Selects one of the three output compare units.
+/// OCR4C is used to define TOP and has no output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Channel {
+    A,
+    B,
+    D,
+}
+
+impl Channel {
+    #[inline]
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// The COM4A and COM4B bits in TCCR4A and the COM4D bits in
+    /// TCCR4C are in the same positions as the COM1A, COM1B and
+    /// COM1C bits of Timer1.
+    #[inline]
+    fn com_bits(&self, mode: CompareOutputMode) -> u8 {
+        let channel = match *self {
+            Channel::A => timer1::Channel::A,
+            Channel::B => timer1::Channel::B,
+            Channel::D => timer1::Channel::C,
+        };
+        mode.bits(channel).bits()
+    }
+
+    #[inline]
+    fn com_mask(&self) -> u8 {
+        self.com_bits(CompareOutputMode::Set)
+    }
+}
+
+/// Divides the timer clock to give the dead time counter clock
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeadTimePrescaler {
+    Div1,
+    Div2,
+    Div4,
+    Div8,
+}
+
+impl DeadTimePrescaler {
+    #[inline]
+    fn bits(&self) -> Tc4Tccr4bFlags {
+        Tc4Tccr4bFlags::from_bits((*self as u8) << 4)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    CompareA,
+    CompareB,
+    CompareD,
+    Overflow,
+}
+
+const NUM_INTERRUPTS: usize = 4;
+
+impl Interrupt {
+    #[inline]
+    fn bits(&self) -> Tc4Timsk4Flags {
+        use self::Interrupt::*;
+        match *self {
+            CompareA => Tc4Timsk4Flags::OCIE4A,
+            CompareB => Tc4Timsk4Flags::OCIE4B,
+            CompareD => Tc4Timsk4Flags::OCIE4D,
+            Overflow => Tc4Timsk4Flags::TOIE4,
+        }
+    }
+
+    #[inline]
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// The functions to call from the timer4 interrupt vectors
+static mut HANDLERS: [Option<fn()>; NUM_INTERRUPTS] = [None; NUM_INTERRUPTS];
+
+/// Register a function to be called from the interrupt vector.
+/// The interrupt itself is enabled via Timer::interrupt() or
+/// Handle::enable_interrupt().
+pub fn set_interrupt_handler(interrupt: Interrupt, handler: Option<fn()>) {
+    interrupt_free(|_cs| unsafe {
+        write_volatile(&mut HANDLERS[interrupt.index()], handler);
+    });
+}
+
+#[inline]
+fn dispatch(interrupt: Interrupt) {
+    unsafe {
+        if let Some(handler) = read_volatile(&HANDLERS[interrupt.index()]) {
+            handler();
+        }
+    }
+}
+
+fn timer4_compare_a() {
+    dispatch(Interrupt::CompareA);
+}
+
+fn timer4_compare_b() {
+    dispatch(Interrupt::CompareB);
+}
+
+fn timer4_compare_d() {
+    dispatch(Interrupt::CompareD);
+}
+
+fn timer4_overflow() {
+    dispatch(Interrupt::Overflow);
+}
+
+irq_handler!(TIMER4_COMPA, timer4_compare_a);
+irq_handler!(TIMER4_COMPB, timer4_compare_b);
+irq_handler!(TIMER4_COMPD, timer4_compare_d);
+irq_handler!(TIMER4_OVF, timer4_overflow);
+
+/// Write a 10-bit value; the high bits must be written to TC4H first.
+/// Must be called with interrupts disabled.
+#[inline]
+unsafe fn write10(reg: &RW<u8>, value: u16) {
+    (*TC4.get()).tc4h.write((value >> 8) as u8);
+    reg.write(value as u8);
+}
+
+/// Read a 10-bit value; reading the low byte latches the high
+/// bits into TC4H.  Must be called with interrupts disabled.
+#[inline]
+unsafe fn read10(reg: &RW<u8>) -> u16 {
+    let low = reg.read() as u16;
+    let high = (*TC4.get()).tc4h.read() as u16;
+    (high << 8) | low
+}
+
+pub struct Timer {
+    wgm: WaveformGenerationMode,
+    clock_input: ClockInput,
+    a: Tc4Tccr4aFlags,
+    b: Tc4Tccr4bFlags,
+    c: Tc4Tccr4cFlags,
+    dead_time: Tc4Dt4Flags,
+    compare: [Option<u16>; 3],
+    top: Option<u16>,
+    interrupts: Tc4Timsk4Flags,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            wgm: WaveformGenerationMode::Normal,
+            clock_input: ClockInput::System,
+            a: Tc4Tccr4aFlags::empty(),
+            b: Tc4Tccr4bFlags::empty(),
+            c: Tc4Tccr4cFlags::empty(),
+            dead_time: Tc4Dt4Flags::empty(),
+            compare: [None; 3],
+            top: None,
+            interrupts: Tc4Timsk4Flags::empty(),
+        }
+    }
+
+    pub fn waveform_generation_mode(mut self, wgm: WaveformGenerationMode) -> Self {
+        self.wgm = wgm;
+        self
+    }
+
+    /// Returns the most recently selected waveform generation mode
+    pub fn mode(&self) -> WaveformGenerationMode {
+        self.wgm
+    }
+
+    pub fn clock_source(mut self, src: ClockSource) -> Self {
+        self.b |= src.bits();
+        self
+    }
+
+    /// Select the clock that feeds the prescaler.  The PLL is started
+    /// when the timer is configured if one of the PLL inputs is selected.
+    pub fn clock_input(mut self, input: ClockInput) -> Self {
+        self.clock_input = input;
+        self
+    }
+
+    /// Set the OCR4C value, which defines TOP.
+    pub fn top(mut self, value: u16) -> Self {
+        self.top = Some(value);
+        self
+    }
+
+    /// Set the compare value for a channel
+    pub fn output_compare(mut self, channel: Channel, value: u16) -> Self {
+        self.compare[channel.index()] = Some(value);
+        self
+    }
+
+    /// Set the behavior of the OC4x pin(s) for a channel.  In the PWM
+    /// modes, Toggle enables both OC4x and the complementary ~OC4x output,
+    /// separated by the dead time.  The pins must also be configured as
+    /// outputs for the waveform to be visible.
+    pub fn compare_output_mode(mut self, channel: Channel, mode: CompareOutputMode) -> Self {
+        let mask = channel.com_mask();
+        let bits = channel.com_bits(mode);
+        match channel {
+            Channel::A | Channel::B => {
+                self.a -= Tc4Tccr4aFlags::from_bits(mask);
+                self.a |= Tc4Tccr4aFlags::from_bits(bits);
+            }
+            Channel::D => {
+                self.c -= Tc4Tccr4cFlags::from_bits(mask);
+                self.c |= Tc4Tccr4cFlags::from_bits(bits);
+            }
+        }
+        self
+    }
+
+    /// Set the dead time inserted before OC4x (`high`) and ~OC4x (`low`)
+    /// go high in the complementary PWM output modes.  Each is a 4-bit
+    /// count of prescaled dead time clock cycles.
+    pub fn dead_time(mut self, prescaler: DeadTimePrescaler, high: u8, low: u8) -> Self {
+        self.b -= DTPS4_MASK;
+        self.b |= prescaler.bits();
+        self.dead_time = Tc4Dt4Flags::from_bits(((high & 0x0f) << 4) | (low & 0x0f));
+        self
+    }
+
+    /// Enable an interrupt.  Use set_interrupt_handler() to register
+    /// the function that will be called from the interrupt.
+    pub fn interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.interrupts |= interrupt.bits();
+        self
+    }
+
+    /// Apply the configuration to the hardware.  The timer's clock
+    /// (and the PLL, if selected) remains enabled until the returned
+    /// Handle is dropped.
+    /// Interrupts that were enabled prior to this call are left enabled.
+    pub fn configure(self) -> Handle {
+        let power = power::acquire(power::Peripheral::Timer4);
+        let pll = match self.clock_input {
+            ClockInput::System => None,
+            _ => Some(pll::acquire()),
+        };
+
+        let mut a = self.a;
+        let mut c = self.c;
+        if self.wgm.is_pwm() {
+            // Enable PWM for each channel that is connected to its pin
+            if a & Tc4Tccr4aFlags::from_bits(Channel::A.com_mask()) != Tc4Tccr4aFlags::empty() {
+                a |= PWM4A;
+            }
+            if a & Tc4Tccr4aFlags::from_bits(Channel::B.com_mask()) != Tc4Tccr4aFlags::empty() {
+                a |= PWM4B;
+            }
+            if c & Tc4Tccr4cFlags::from_bits(Channel::D.com_mask()) != Tc4Tccr4cFlags::empty() {
+                c |= PWM4D;
+            }
+        }
+        // The upper nibble of TCCR4C shadows the COM4A and COM4B bits
+        // of TCCR4A; make sure that writing TCCR4C doesn't clobber them.
+        c = Tc4Tccr4cFlags::from_bits((c.bits() & 0x0f) | (a.bits() & 0xf0));
+
+        unsafe {
+            interrupt_free(|_cs| {
+                (*PLL.get())
+                    .pllfrq
+                    .modify(|x| (x - PLLTM_MASK) | self.clock_input.bits());
+
+                let tc4 = &(*TC4.get());
+                // Stop the clock while we reconfigure
+                tc4.tccr4b.write(Tc4Tccr4bFlags::empty());
+                tc4.tccr4a.write(a);
+                tc4.tccr4c.write(c);
+                tc4.tccr4d.write(self.wgm.bits());
+                tc4.dt4.write(self.dead_time);
+                write10(&tc4.tcnt4, 0);
+
+                if let Some(top) = self.top {
+                    write10(&tc4.ocr4c, top);
+                }
+                if let Some(compare) = self.compare[Channel::A.index()] {
+                    write10(&tc4.ocr4a, compare);
+                }
+                if let Some(compare) = self.compare[Channel::B.index()] {
+                    write10(&tc4.ocr4b, compare);
+                }
+                if let Some(compare) = self.compare[Channel::D.index()] {
+                    write10(&tc4.ocr4d, compare);
+                }
+
+                tc4.timsk4.modify(|x| x | self.interrupts);
+                tc4.tccr4b.write(self.b);
+            });
+        }
+        Handle {
+            _power: power,
+            _pll: pll,
+        }
+    }
+}
+
+/// Represents a configured Timer4.  Provides access to the
+/// counter and compare registers without reconfiguring the timer.
+#[must_use]
+pub struct Handle {
+    _power: PowerHandle,
+    _pll: Option<PllHandle>,
+}
+
+impl Handle {
+    /// Returns the current value of TCNT4
+    pub fn counter(&self) -> u16 {
+        let _cs = CriticalSection::new();
+        unsafe { read10(&(*TC4.get()).tcnt4) }
+    }
+
+    pub fn set_counter(&self, value: u16) {
+        let _cs = CriticalSection::new();
+        unsafe { write10(&(*TC4.get()).tcnt4, value) }
+    }
+
+    /// Returns TOP, as defined by OCR4C
+    pub fn top(&self) -> u16 {
+        let _cs = CriticalSection::new();
+        unsafe { read10(&(*TC4.get()).ocr4c) }
+    }
+
+    pub fn set_top(&self, value: u16) {
+        let _cs = CriticalSection::new();
+        unsafe { write10(&(*TC4.get()).ocr4c, value) }
+    }
+
+    /// Returns the compare value for a channel
+    pub fn output_compare(&self, channel: Channel) -> u16 {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let tc4 = &(*TC4.get());
+            match channel {
+                Channel::A => read10(&tc4.ocr4a),
+                Channel::B => read10(&tc4.ocr4b),
+                Channel::D => read10(&tc4.ocr4d),
+            }
+        }
+    }
+
+    /// Update the compare value for a channel.  In the PWM modes
+    /// the hardware double buffers this until the counter reaches TOP
+    /// or BOTTOM.
+    pub fn set_output_compare(&self, channel: Channel, value: u16) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let tc4 = &(*TC4.get());
+            match channel {
+                Channel::A => write10(&tc4.ocr4a, value),
+                Channel::B => write10(&tc4.ocr4b, value),
+                Channel::D => write10(&tc4.ocr4d, value),
+            }
+        }
+    }
+
+    /// Strobe a compare match for a channel without setting the
+    /// interrupt flag or resetting the counter.  Only valid in
+    /// Normal mode.
+    pub fn force_output_compare(&self, channel: Channel) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let tc4 = &(*TC4.get());
+            match channel {
+                Channel::A => tc4.tccr4a.modify(|x| x | FOC4A),
+                Channel::B => tc4.tccr4a.modify(|x| x | FOC4B),
+                Channel::D => tc4.tccr4c.modify(|x| x | FOC4D),
+            }
+        }
+    }
+
+    pub fn enable_interrupt(&self, interrupt: Interrupt) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC4.get()).timsk4.modify(|x| x | interrupt.bits());
+        }
+    }
+
+    pub fn disable_interrupt(&self, interrupt: Interrupt) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            (*TC4.get()).timsk4.modify(|x| x - interrupt.bits());
+        }
+    }
+}
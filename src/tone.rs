@@ -0,0 +1,177 @@
+//! Square wave generation for driving a piezo buzzer from the OC3A
+//! (PC6) pin.  Timer3 runs in CTC mode and toggles the pin on each
+//! compare match, so no CPU time is used while a note is sounding.
+//!
+//! ```
+//! const MELODY: &[Note] = &[
+//!     Note::new(440, Ticks::milliseconds(200)),
+//!     Note::rest(Ticks::milliseconds(100)),
+//!     Note::new(880, Ticks::milliseconds(200)),
+//! ];
+//! events.play(Tone::new(), MELODY)?;
+//! ```
+use mcu::{PortcSignalFlags, PORTC};
+use futures::{Async, Future, Poll};
+use eventloop::Ticks;
+use clock;
+use timer3::{self, Channel, ClockSource, CompareOutputMode, WaveformGenerationMode};
+
+/// A note in a melody
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Note {
+    /// The frequency in Hz, or 0 for silence
+    pub frequency: u16,
+    pub duration: Ticks,
+}
+
+impl Note {
+    pub const fn new(frequency: u16, duration: Ticks) -> Self {
+        Self {
+            frequency,
+            duration,
+        }
+    }
+
+    /// A period of silence
+    pub const fn rest(duration: Ticks) -> Self {
+        Self {
+            frequency: 0,
+            duration,
+        }
+    }
+}
+
+const PRESCALERS: [ClockSource; 5] = [
+    ClockSource::Prescale1,
+    ClockSource::Prescale8,
+    ClockSource::Prescale64,
+    ClockSource::Prescale256,
+    ClockSource::Prescale1024,
+];
+
+/// Pick the smallest prescaler that allows the compare value to fit
+/// in 16 bits, as that gives the most accurate frequency.
+fn timer_settings(frequency: u16) -> Option<(ClockSource, u16)> {
+    let cpu = clock::frequency();
+    for source in PRESCALERS.iter() {
+        let divisor = source.divisor()?;
+        let count = cpu / (2 * divisor * frequency as u32);
+        if count == 0 {
+            return None;
+        }
+        if count <= 0x1_0000 {
+            return Some((*source, (count - 1) as u16));
+        }
+    }
+    None
+}
+
+/// Owns Timer3 and the OC3A pin while a tone is being generated
+pub struct Tone {
+    timer: Option<timer3::Handle>,
+}
+
+impl Tone {
+    pub fn new() -> Self {
+        Self { timer: None }
+    }
+
+    /// Start generating a square wave at `frequency` Hz, replacing
+    /// any tone that is currently playing.  A frequency of 0 is
+    /// equivalent to stop().  Fails if the frequency cannot be
+    /// generated from the current CPU clock.
+    pub fn start(&mut self, frequency: u16) -> Result<(), ()> {
+        if frequency == 0 {
+            self.stop();
+            return Ok(());
+        }
+        let (source, compare) = timer_settings(frequency).ok_or(())?;
+
+        unsafe {
+            (*PORTC.get()).ddrc.modify(|x| x | PortcSignalFlags::PC6);
+        }
+        self.timer = Some(
+            timer3::Timer::new()
+                .waveform_generation_mode(WaveformGenerationMode::ClearOnTimerMatchOutputCompare)
+                .clock_source(source)
+                .output_compare(Channel::A, compare)
+                .compare_output_mode(Channel::A, CompareOutputMode::Toggle)
+                .configure(),
+        );
+        Ok(())
+    }
+
+    /// Silence the buzzer and release the timer
+    pub fn stop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer.set_compare_output_mode(Channel::A, CompareOutputMode::Disconnected);
+            unsafe {
+                (*PORTC.get()).portc.modify(|x| x - PortcSignalFlags::PC6);
+            }
+        }
+    }
+
+    /// Returns true if a tone is currently being generated
+    pub fn is_playing(&self) -> bool {
+        self.timer.is_some()
+    }
+}
+
+impl Drop for Tone {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A Future that plays a sequence of notes and completes when the
+/// last note has finished.  Notes that cannot be generated are
+/// played as rests.
+pub struct Melody {
+    tone: Tone,
+    notes: &'static [Note],
+    next: usize,
+    /// When the current note started
+    started: Option<Ticks>,
+}
+
+impl Melody {
+    pub fn new(tone: Tone, notes: &'static [Note]) -> Self {
+        Self {
+            tone,
+            notes,
+            next: 0,
+            started: None,
+        }
+    }
+}
+
+impl Future for Melody {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let now = Ticks::current();
+
+        if let Some(started) = self.started {
+            let duration = self.notes[self.next - 1].duration;
+            if now.wrapping_sub(started) < duration {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        match self.notes.get(self.next) {
+            Some(note) => {
+                if self.tone.start(note.frequency).is_err() {
+                    self.tone.stop();
+                }
+                self.next += 1;
+                self.started = Some(now);
+                Ok(Async::NotReady)
+            }
+            None => {
+                self.tone.stop();
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! TWI (I2C) master driver.  On the atmega32u4 SCL is PD0 and SDA
+//! is PD1; external pull up resistors are required.
+//!
+//! Transfers are run by a state machine in the TWI interrupt handler.
+//! The data to be written is copied into a static buffer, so transfers
+//! are limited to BUFFER_SIZE bytes in each direction.  As with the
+//! SPI driver, the futures take ownership of the Twi and hand it back
+//! on completion, along with any data that was read:
+//!
+//! ```
+//! let twi = Twi::new(400_000).unwrap();
+//! events.spawn(
+//!     twi.write_read(MCP23018, &[GPIOA], 1)
+//!         .map(|(twi, data)| { ... })
+//!         .map_err(|(twi, err)| { ... }),
+//! )?;
+//! ```
+use mcu::{PortdSignalFlags, TwiTwcrFlags, TwiTwsrFlags, PORTD, TWI};
+use mutex::Mutex;
+use power::{self, PowerHandle};
+use arrayvec::ArrayVec;
+use futures::{Async, Future, Poll};
+use clock;
+use fcpu;
+use sleep;
+
+const TWINT: TwiTwcrFlags = TwiTwcrFlags::from_bits(1 << 7);
+const TWEA: TwiTwcrFlags = TwiTwcrFlags::from_bits(1 << 6);
+const TWSTA: TwiTwcrFlags = TwiTwcrFlags::from_bits(1 << 5);
+const TWSTO: TwiTwcrFlags = TwiTwcrFlags::from_bits(1 << 4);
+const TWEN: TwiTwcrFlags = TwiTwcrFlags::from_bits(1 << 2);
+const TWIE: TwiTwcrFlags = TwiTwcrFlags::from_bits(1 << 0);
+
+const TWPS_MASK: TwiTwsrFlags = TwiTwsrFlags::from_bits(0b11);
+
+const SCL: PortdSignalFlags = PortdSignalFlags::PD0;
+const SDA: PortdSignalFlags = PortdSignalFlags::PD1;
+
+// Master mode status codes from TWSR, with the prescaler bits masked off
+const STATUS_MASK: u8 = 0xf8;
+const START: u8 = 0x08;
+const REPEATED_START: u8 = 0x10;
+const SLA_W_ACK: u8 = 0x18;
+const SLA_W_NACK: u8 = 0x20;
+const DATA_TX_ACK: u8 = 0x28;
+const DATA_TX_NACK: u8 = 0x30;
+const ARBITRATION_LOST: u8 = 0x38;
+const SLA_R_ACK: u8 = 0x40;
+const SLA_R_NACK: u8 = 0x48;
+const DATA_RX_ACK: u8 = 0x50;
+const DATA_RX_NACK: u8 = 0x58;
+const BUS_ERROR: u8 = 0x00;
+
+/// The maximum number of bytes that can be written or read
+/// in a single transfer
+pub const BUFFER_SIZE: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The requested bit rate cannot be generated from the current
+    /// CPU clock
+    UnsupportedBitRate,
+    /// The transfer is larger than BUFFER_SIZE
+    TooLong,
+    /// No device acknowledged the address
+    AddressNack,
+    /// The device did not acknowledge a data byte
+    DataNack,
+    /// Another master took control of the bus
+    ArbitrationLost,
+    /// An illegal START or STOP condition was detected.  The bus
+    /// may need to be recovered with Twi::recover_bus().
+    BusError,
+}
+
+/// Computes the TWBR value and TWPS prescaler bits that give a bit rate
+/// of at most `bit_rate` Hz for the current CPU frequency.
+fn bit_rate_settings(bit_rate: u32) -> Option<(u8, u8)> {
+    let cpu = clock::frequency();
+    if bit_rate == 0 || cpu / bit_rate < 16 {
+        return None;
+    }
+    // SCL = cpu / (16 + 2 * TWBR * 4^TWPS)
+    let half_cycles = (cpu / bit_rate - 16 + 1) / 2;
+    for twps in 0..4 {
+        let prescale = 1 << (2 * twps);
+        let twbr = (half_cycles + prescale - 1) / prescale;
+        if twbr <= 0xff {
+            return Some((twbr as u8, twps as u8));
+        }
+    }
+    None
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Write,
+    Read,
+    Done,
+}
+
+struct State {
+    phase: Phase,
+    address: u8,
+    buf: [u8; BUFFER_SIZE],
+    write_len: usize,
+    read_len: usize,
+    pos: usize,
+    result: Result<(), Error>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    phase: Phase::Idle,
+    address: 0,
+    buf: [0; BUFFER_SIZE],
+    write_len: 0,
+    read_len: 0,
+    pos: 0,
+    result: Ok(()),
+});
+
+#[inline]
+fn write_twcr(flags: TwiTwcrFlags) {
+    unsafe {
+        (*TWI.get()).twcr.write(flags);
+    }
+}
+
+/// Release the bus and record the outcome of the transfer
+fn finish(state: &mut State, result: Result<(), Error>, stop: bool) {
+    if stop {
+        write_twcr(TWINT | TWSTO | TWEN);
+    } else {
+        write_twcr(TWINT | TWEN);
+    }
+    state.result = result;
+    state.phase = Phase::Done;
+    sleep::set_event_pending();
+}
+
+/// Acknowledge the next byte unless it is the last one to be read
+fn continue_read(state: &State) {
+    if state.read_len - state.pos > 1 {
+        write_twcr(TWINT | TWEA | TWEN | TWIE);
+    } else {
+        write_twcr(TWINT | TWEN | TWIE);
+    }
+}
+
+fn twi_interrupt() {
+    let mut state = STATE.lock();
+    let twi = unsafe { &(*TWI.get()) };
+    let status = twi.twsr.read().bits() & STATUS_MASK;
+
+    match status {
+        START | REPEATED_START => {
+            let rw = if state.phase == Phase::Read { 1 } else { 0 };
+            unsafe {
+                twi.twdr.write((state.address << 1) | rw);
+            }
+            write_twcr(TWINT | TWEN | TWIE);
+        }
+        SLA_W_ACK | DATA_TX_ACK => {
+            if state.pos < state.write_len {
+                let byte = state.buf[state.pos];
+                state.pos += 1;
+                unsafe {
+                    twi.twdr.write(byte);
+                }
+                write_twcr(TWINT | TWEN | TWIE);
+            } else if state.read_len > 0 {
+                state.phase = Phase::Read;
+                state.pos = 0;
+                write_twcr(TWINT | TWSTA | TWEN | TWIE);
+            } else {
+                finish(&mut state, Ok(()), true);
+            }
+        }
+        SLA_R_ACK => continue_read(&state),
+        DATA_RX_ACK | DATA_RX_NACK => {
+            let pos = state.pos;
+            state.buf[pos] = twi.twdr.read();
+            state.pos += 1;
+            if status == DATA_RX_NACK || state.pos >= state.read_len {
+                finish(&mut state, Ok(()), true);
+            } else {
+                continue_read(&state);
+            }
+        }
+        SLA_W_NACK | SLA_R_NACK => finish(&mut state, Err(Error::AddressNack), true),
+        DATA_TX_NACK => finish(&mut state, Err(Error::DataNack), true),
+        ARBITRATION_LOST => finish(&mut state, Err(Error::ArbitrationLost), false),
+        BUS_ERROR | _ => finish(&mut state, Err(Error::BusError), true),
+    }
+}
+
+irq_handler!(TWI, twi_interrupt);
+
+pub struct Twi {
+    _power: PowerHandle,
+    twbr: u8,
+    twps: u8,
+}
+
+impl Twi {
+    /// Enable the TWI as a bus master with an SCL frequency of
+    /// at most `bit_rate` Hz.
+    pub fn new(bit_rate: u32) -> Result<Self, Error> {
+        let (twbr, twps) = bit_rate_settings(bit_rate).ok_or(Error::UnsupportedBitRate)?;
+        let twi = Self {
+            _power: power::acquire(power::Peripheral::Twi),
+            twbr,
+            twps,
+        };
+        twi.enable();
+        Ok(twi)
+    }
+
+    fn enable(&self) {
+        STATE.lock().phase = Phase::Idle;
+        unsafe {
+            let twi = &(*TWI.get());
+            twi.twbr.write(self.twbr);
+            twi.twsr
+                .modify(|x| (x - TWPS_MASK) | TwiTwsrFlags::from_bits(self.twps));
+            twi.twcr.write(TWEN);
+        }
+    }
+
+    /// Attempt to free a bus that is held low by a device that was
+    /// interrupted part way through a transfer, for example by a reset
+    /// of this MCU.  SCL is clocked until the device releases SDA and
+    /// then a STOP condition is generated.  Returns true if SDA is
+    /// high afterwards.
+    pub fn recover_bus(&mut self) -> bool {
+        unsafe {
+            (*TWI.get()).twcr.write(TwiTwcrFlags::empty());
+            let portd = &(*PORTD.get());
+            let sda_high = || (portd.pind.read() & SDA) == SDA;
+            // Open drain emulation: drive low by making the pin an output
+            // (PORT is low), release by making it an input.
+            portd.portd.modify(|x| x - (SCL | SDA));
+            portd.ddrd.modify(|x| x - (SCL | SDA));
+
+            for _ in 0..9 {
+                if sda_high() {
+                    break;
+                }
+                portd.ddrd.modify(|x| x | SCL);
+                fcpu::delay_us(5);
+                portd.ddrd.modify(|x| x - SCL);
+                fcpu::delay_us(5);
+            }
+
+            // STOP: SDA rises while SCL is high
+            portd.ddrd.modify(|x| x | SDA);
+            fcpu::delay_us(5);
+            portd.ddrd.modify(|x| x - SDA);
+            fcpu::delay_us(5);
+
+            let recovered = sda_high();
+            self.enable();
+            recovered
+        }
+    }
+
+    /// Write `data` to the device at the 7-bit `address`
+    pub fn write(self, address: u8, data: &[u8]) -> Transfer {
+        self.start(address, data, 0)
+    }
+
+    /// Read `len` bytes from the device at the 7-bit `address`
+    pub fn read(self, address: u8, len: usize) -> Transfer {
+        self.start(address, &[], len)
+    }
+
+    /// Write `data` to the device at the 7-bit `address`, then issue
+    /// a repeated START and read `len` bytes.  This is the usual way
+    /// to read a register.
+    pub fn write_read(self, address: u8, data: &[u8], len: usize) -> Transfer {
+        self.start(address, data, len)
+    }
+
+    fn start(self, address: u8, data: &[u8], read_len: usize) -> Transfer {
+        if data.len() > BUFFER_SIZE || read_len > BUFFER_SIZE {
+            return Transfer {
+                twi: Some(self),
+                error: Some(Error::TooLong),
+            };
+        }
+        {
+            let mut state = STATE.lock();
+            state.address = address;
+            state.buf[..data.len()].copy_from_slice(data);
+            state.write_len = data.len();
+            state.read_len = read_len;
+            state.pos = 0;
+            state.phase = if data.len() > 0 || read_len == 0 {
+                Phase::Write
+            } else {
+                Phase::Read
+            };
+            unsafe {
+                // Wait for the STOP from any prior transfer to complete
+                while ((*TWI.get()).twcr.read() & TWSTO) == TWSTO {}
+            }
+            write_twcr(TWINT | TWSTA | TWEN | TWIE);
+        }
+        Transfer {
+            twi: Some(self),
+            error: None,
+        }
+    }
+}
+
+impl Drop for Twi {
+    fn drop(&mut self) {
+        write_twcr(TwiTwcrFlags::empty());
+    }
+}
+
+/// The data read by a transfer
+pub type Data = ArrayVec<[u8; BUFFER_SIZE]>;
+
+pub struct Transfer {
+    twi: Option<Twi>,
+    /// An error detected before the transfer started
+    error: Option<Error>,
+}
+
+impl Future for Transfer {
+    type Item = (Twi, Data);
+    type Error = (Twi, Error);
+
+    fn poll(&mut self) -> Poll<(Twi, Data), (Twi, Error)> {
+        if let Some(error) = self.error.take() {
+            return Err((self.twi.take().expect("polled after completion"), error));
+        }
+
+        let mut state = STATE.lock();
+        if state.phase != Phase::Done {
+            return Ok(Async::NotReady);
+        }
+        state.phase = Phase::Idle;
+        let twi = self.twi.take().expect("polled after completion");
+
+        match state.result {
+            Ok(()) => {
+                let mut data = Data::new();
+                data.extend(state.buf[..state.read_len].iter().cloned());
+                Ok(Async::Ready((twi, data)))
+            }
+            Err(error) => Err((twi, error)),
+        }
+    }
+}
@@ -0,0 +1,403 @@
+//! Interrupt driven driver for USART1, which is connected to the
+//! RXD1 (PD2) and TXD1 (PD3) pins on the atmega32u4.
+//!
+//! Received bytes and bytes waiting to be transmitted are held in
+//! ring buffers that are serviced by the RX and UDRE interrupts, so
+//! the event loop only needs to poll the futures and streams below.
+//!
+//! ```
+//! let mut serial = Usart::new(Config::new(115_200)).unwrap();
+//! writeln!(serial, "hello").unwrap();
+//! events.spawn(serial.bytes().for_each(|byte| { ... }))?;
+//! ```
+use mcu::{Usart1Ucsr1aFlags, Usart1Ucsr1bFlags, Usart1Ucsr1cFlags, USART1};
+use mutex::Mutex;
+use ringbuffer::RingBuffer;
+use power::{self, PowerHandle};
+use futures::{Async, Future, Poll, Stream};
+use core::fmt;
+use clock;
+use sleep;
+
+const UDRE: Usart1Ucsr1aFlags = Usart1Ucsr1aFlags::from_bits(1 << 5);
+const FE: Usart1Ucsr1aFlags = Usart1Ucsr1aFlags::from_bits(1 << 4);
+const DOR: Usart1Ucsr1aFlags = Usart1Ucsr1aFlags::from_bits(1 << 3);
+const UPE: Usart1Ucsr1aFlags = Usart1Ucsr1aFlags::from_bits(1 << 2);
+const U2X: Usart1Ucsr1aFlags = Usart1Ucsr1aFlags::from_bits(1 << 1);
+
+const RXCIE: Usart1Ucsr1bFlags = Usart1Ucsr1bFlags::from_bits(1 << 7);
+const UDRIE: Usart1Ucsr1bFlags = Usart1Ucsr1bFlags::from_bits(1 << 5);
+const RXEN: Usart1Ucsr1bFlags = Usart1Ucsr1bFlags::from_bits(1 << 4);
+const TXEN: Usart1Ucsr1bFlags = Usart1Ucsr1bFlags::from_bits(1 << 3);
+
+const UPM_EVEN: Usart1Ucsr1cFlags = Usart1Ucsr1cFlags::from_bits(0b10 << 4);
+const UPM_ODD: Usart1Ucsr1cFlags = Usart1Ucsr1cFlags::from_bits(0b11 << 4);
+const USBS: Usart1Ucsr1cFlags = Usart1Ucsr1cFlags::from_bits(1 << 3);
+
+/// The largest acceptable difference between the requested and
+/// actual baud rates, in tenths of a percent
+const MAX_BAUD_ERROR_PERMILLE: u32 = 20;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+impl DataBits {
+    /// Returns the UCSZ1 bits for UCSR1C.  UCSZ2 is only used for
+    /// 9-bit frames, which are not supported.
+    #[inline]
+    fn bits(&self) -> Usart1Ucsr1cFlags {
+        Usart1Ucsr1cFlags::from_bits((*self as u8) << 1)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The requested baud rate cannot be generated within 2% from the
+    /// current CPU clock; `actual` is the closest achievable rate.
+    BaudRate { actual: u32 },
+    /// A baud rate of zero was requested
+    ZeroBaudRate,
+    /// A received frame did not have a valid stop bit
+    Framing,
+    /// A received frame had the wrong parity
+    Parity,
+    /// Bytes were lost because they were not collected quickly enough
+    Overrun,
+}
+
+/// Computes the UBRR1 value and U2X setting that give the closest match
+/// to `baud` for the current CPU frequency.  Returns (ubrr, u2x, actual),
+/// or Error::ZeroBaudRate if `baud` is zero.
+pub fn baud_rate_settings(baud: u32) -> Result<(u16, bool, u32), Error> {
+    if baud == 0 {
+        return Err(Error::ZeroBaudRate);
+    }
+    let cpu = clock::frequency();
+
+    let settings = |divisor: u32| -> (u16, u32) {
+        // Round to the nearest UBRR value
+        let ubrr = ((cpu + (divisor * baud) / 2) / (divisor * baud)).max(1) - 1;
+        let ubrr = ubrr.min(0xfff);
+        (ubrr as u16, cpu / (divisor * (ubrr + 1)))
+    };
+    let error = |actual: u32| if actual > baud {
+        actual - baud
+    } else {
+        baud - actual
+    };
+
+    let (normal_ubrr, normal_actual) = settings(16);
+    let (double_ubrr, double_actual) = settings(8);
+
+    // Prefer normal speed on a tie, as it samples each bit more times
+    if error(double_actual) < error(normal_actual) {
+        Ok((double_ubrr, true, double_actual))
+    } else {
+        Ok((normal_ubrr, false, normal_actual))
+    }
+}
+
+/// Serial port configuration; defaults to 8N1
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    baud: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+}
+
+impl Config {
+    pub fn new(baud: u32) -> Self {
+        Self {
+            baud,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+
+    pub fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+}
+
+const BUFFER_SIZE: usize = 32;
+
+struct Buffers {
+    rx: RingBuffer<u8, [u8; BUFFER_SIZE]>,
+    tx: RingBuffer<u8, [u8; BUFFER_SIZE]>,
+    /// The first receive error since the stream last polled
+    rx_error: Option<Error>,
+}
+
+static BUFFERS: Mutex<Buffers> = Mutex::new(Buffers {
+    rx: RingBuffer::new([0; BUFFER_SIZE]),
+    tx: RingBuffer::new([0; BUFFER_SIZE]),
+    rx_error: None,
+});
+
+fn usart1_rx() {
+    let mut buffers = BUFFERS.lock();
+    unsafe {
+        let usart = &(*USART1.get());
+        // The status must be read before UDR1
+        let status = usart.ucsr1a.read();
+        let byte = usart.udr1.read();
+
+        let error = if (status & FE) == FE {
+            Some(Error::Framing)
+        } else if (status & UPE) == UPE {
+            Some(Error::Parity)
+        } else if (status & DOR) == DOR {
+            Some(Error::Overrun)
+        } else {
+            None
+        };
+
+        if error.is_some() {
+            if buffers.rx_error.is_none() {
+                buffers.rx_error = error;
+            }
+        } else if buffers.rx.push(byte).is_err() && buffers.rx_error.is_none() {
+            buffers.rx_error = Some(Error::Overrun);
+        }
+    }
+    sleep::set_event_pending();
+}
+
+fn usart1_udre() {
+    let mut buffers = BUFFERS.lock();
+    unsafe {
+        let usart = &(*USART1.get());
+        match buffers.tx.pop() {
+            Some(byte) => usart.udr1.write(byte),
+            None => usart.ucsr1b.modify(|x| x - UDRIE),
+        }
+    }
+    sleep::set_event_pending();
+}
+
+irq_handler!(USART1_RX, usart1_rx);
+irq_handler!(USART1_UDRE, usart1_udre);
+
+pub struct Usart {
+    _power: PowerHandle,
+}
+
+impl Usart {
+    /// Configure USART1 and enable the transmitter and receiver.
+    /// Fails if the baud rate is zero or cannot be generated accurately
+    /// enough from the current CPU clock.
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let (ubrr, u2x, actual) = baud_rate_settings(config.baud)?;
+        let error = if actual > config.baud {
+            actual - config.baud
+        } else {
+            config.baud - actual
+        };
+        if error * 1000 / config.baud > MAX_BAUD_ERROR_PERMILLE {
+            return Err(Error::BaudRate { actual });
+        }
+
+        let mut c = config.data_bits.bits();
+        match config.parity {
+            Parity::None => {}
+            Parity::Even => c |= UPM_EVEN,
+            Parity::Odd => c |= UPM_ODD,
+        }
+        if config.stop_bits == StopBits::Two {
+            c |= USBS;
+        }
+
+        let power = power::acquire(power::Peripheral::Usart1);
+        {
+            let mut buffers = BUFFERS.lock();
+            buffers.rx.clear();
+            buffers.tx.clear();
+            buffers.rx_error = None;
+            unsafe {
+                let usart = &(*USART1.get());
+                usart.ucsr1b.write(Usart1Ucsr1bFlags::empty());
+                usart.ubrr1.write(ubrr);
+                usart.ucsr1a.write(if u2x {
+                    U2X
+                } else {
+                    Usart1Ucsr1aFlags::empty()
+                });
+                usart.ucsr1c.write(c);
+                usart.ucsr1b.write(RXCIE | RXEN | TXEN);
+            }
+        }
+
+        Ok(Self { _power: power })
+    }
+
+    /// Queue a byte for transmission, returning false if the transmit
+    /// buffer is full.
+    pub fn try_write_byte(&self, byte: u8) -> bool {
+        let mut buffers = BUFFERS.lock();
+        if buffers.tx.push(byte).is_err() {
+            return false;
+        }
+        unsafe {
+            (*USART1.get()).ucsr1b.modify(|x| x | UDRIE);
+        }
+        true
+    }
+
+    /// Move a byte from the transmit buffer to the data register if
+    /// it is ready.  This allows the blocking functions to make
+    /// progress even when interrupts are disabled.
+    fn feed_transmitter(&self) {
+        let mut buffers = BUFFERS.lock();
+        unsafe {
+            let usart = &(*USART1.get());
+            if (usart.ucsr1a.read() & UDRE) == UDRE {
+                if let Some(next) = buffers.tx.pop() {
+                    usart.udr1.write(next);
+                }
+            }
+        }
+    }
+
+    /// Queue a byte for transmission, waiting for space in the
+    /// transmit buffer if necessary.
+    pub fn write_byte(&self, byte: u8) {
+        while !self.try_write_byte(byte) {
+            self.feed_transmitter();
+        }
+    }
+
+    /// Wait until all queued bytes have been handed to the transmitter
+    pub fn flush(&self) {
+        while !self.is_flushed() {
+            self.feed_transmitter();
+        }
+    }
+
+    /// Returns the next received byte, if any
+    pub fn read_byte(&self) -> Result<Option<u8>, Error> {
+        let mut buffers = BUFFERS.lock();
+        if let Some(error) = buffers.rx_error.take() {
+            return Err(error);
+        }
+        Ok(buffers.rx.pop())
+    }
+
+    /// Returns true if there are no bytes waiting to be transmitted
+    pub fn is_flushed(&self) -> bool {
+        let buffers = BUFFERS.lock();
+        unsafe { buffers.tx.is_empty() && ((*USART1.get()).ucsr1a.read() & UDRE) == UDRE }
+    }
+
+    /// Returns a Future that queues all of `buf` for transmission
+    /// and then resolves to `buf`.
+    pub fn write_all<B: AsRef<[u8]>>(&self, buf: B) -> WriteAll<B> {
+        WriteAll {
+            buf: Some(buf),
+            pos: 0,
+        }
+    }
+
+    /// Returns a Stream of received bytes.  Receive errors are
+    /// reported through the stream, which continues afterwards.
+    pub fn bytes(&self) -> Bytes {
+        Bytes { _private: () }
+    }
+}
+
+impl Drop for Usart {
+    fn drop(&mut self) {
+        // Let any buffered data drain before turning off the transmitter
+        self.flush();
+        unsafe {
+            (*USART1.get()).ucsr1b.write(Usart1Ucsr1bFlags::empty());
+        }
+    }
+}
+
+pub struct WriteAll<B> {
+    buf: Option<B>,
+    pos: usize,
+}
+
+impl<B: AsRef<[u8]>> Future for WriteAll<B> {
+    type Item = B;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<B, Error> {
+        {
+            let data = self.buf.as_ref().expect("polled after completion").as_ref();
+            let mut buffers = BUFFERS.lock();
+            while self.pos < data.len() && buffers.tx.push(data[self.pos]).is_ok() {
+                self.pos += 1;
+            }
+            unsafe {
+                (*USART1.get()).ucsr1b.modify(|x| x | UDRIE);
+            }
+            if self.pos < data.len() {
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok(Async::Ready(self.buf.take().unwrap()))
+    }
+}
+
+pub struct Bytes {
+    _private: (),
+}
+
+impl Stream for Bytes {
+    type Item = u8;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<u8>, Error> {
+        let mut buffers = BUFFERS.lock();
+        if let Some(error) = buffers.rx_error.take() {
+            return Err(error);
+        }
+        match buffers.rx.pop() {
+            Some(byte) => Ok(Async::Ready(Some(byte))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Blocking; see Usart::write_byte()
+impl fmt::Write for Usart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
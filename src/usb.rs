@@ -0,0 +1,982 @@
+//! USB device controller driver.
+//!
+//! The driver takes care of attaching to the bus, bus reset, suspend
+//! and resume, and the standard requests on the control endpoint.
+//! Everything that is specific to a particular kind of device (the
+//! descriptors, the endpoints that make up the configuration and any
+//! class requests) is supplied by a type implementing `Class`.
+//!
+//! Control transfers are serviced in the USB_COM interrupt handler.
+//! The other endpoints are driven from the event loop through
+//! `InEndpoint` and `OutEndpoint`, whose futures are woken by the
+//! endpoint interrupts:
+//!
+//! ```
+//! let mut usb = Usb::new::<MyDevice>().unwrap();
+//! let ep = usb.in_endpoint(1).unwrap();
+//! events.spawn(ep.write([0u8; 8]).map(|(ep, _report)| { ... }))?;
+//! ```
+use mcu::{UsbDeviceUdaddrFlags, UsbDeviceUdconFlags, UsbDeviceUdienFlags, UsbDeviceUdintFlags,
+          UsbDeviceUecfg0xFlags, UsbDeviceUecfg1xFlags, UsbDeviceUeconxFlags,
+          UsbDeviceUeienxFlags, UsbDeviceUeintxFlags, UsbDeviceUerstFlags,
+          UsbDeviceUesta0xFlags, UsbDeviceUhwconFlags, UsbDeviceUsbconFlags, UsbDevice,
+          USB_DEVICE};
+use mutex::CriticalSection;
+use pll::{self, PllHandle};
+use power::{self, PowerHandle};
+use progmem::ProgSlice;
+use core::ptr::{read_volatile, write_volatile};
+use futures::{Async, Future, Poll};
+use sleep;
+
+const UVREGE: UsbDeviceUhwconFlags = UsbDeviceUhwconFlags::from_bits(1 << 0);
+
+const USBE: UsbDeviceUsbconFlags = UsbDeviceUsbconFlags::from_bits(1 << 7);
+const FRZCLK: UsbDeviceUsbconFlags = UsbDeviceUsbconFlags::from_bits(1 << 5);
+const OTGPADE: UsbDeviceUsbconFlags = UsbDeviceUsbconFlags::from_bits(1 << 4);
+
+const RMWKUP: UsbDeviceUdconFlags = UsbDeviceUdconFlags::from_bits(1 << 1);
+const DETACH: UsbDeviceUdconFlags = UsbDeviceUdconFlags::from_bits(1 << 0);
+
+// UDINT and UDIEN share the same layout
+const WAKEUP: u8 = 1 << 4;
+const EORST: u8 = 1 << 3;
+const SUSP: u8 = 1 << 0;
+
+const ADDEN: UsbDeviceUdaddrFlags = UsbDeviceUdaddrFlags::from_bits(1 << 7);
+
+const STALLRQ: UsbDeviceUeconxFlags = UsbDeviceUeconxFlags::from_bits(1 << 5);
+const STALLRQC: UsbDeviceUeconxFlags = UsbDeviceUeconxFlags::from_bits(1 << 4);
+const RSTDT: UsbDeviceUeconxFlags = UsbDeviceUeconxFlags::from_bits(1 << 3);
+const EPEN: UsbDeviceUeconxFlags = UsbDeviceUeconxFlags::from_bits(1 << 0);
+
+const ALLOC: UsbDeviceUecfg1xFlags = UsbDeviceUecfg1xFlags::from_bits(1 << 1);
+const CFGOK: UsbDeviceUesta0xFlags = UsbDeviceUesta0xFlags::from_bits(1 << 7);
+
+// UEINTX and UEIENX share the same layout
+const FIFOCON: u8 = 1 << 7;
+const RWAL: u8 = 1 << 5;
+const RXSTP: u8 = 1 << 3;
+const RXOUT: u8 = 1 << 2;
+const TXIN: u8 = 1 << 0;
+
+/// The size of the control endpoint's FIFO
+pub const ENDPOINT0_SIZE: u16 = 64;
+
+/// The atmega32u4 has endpoints 0 through 6
+pub const NUM_ENDPOINTS: u8 = 7;
+
+// Standard request codes
+const GET_STATUS: u8 = 0;
+const CLEAR_FEATURE: u8 = 1;
+const SET_FEATURE: u8 = 3;
+const SET_ADDRESS: u8 = 5;
+const GET_DESCRIPTOR: u8 = 6;
+const GET_CONFIGURATION: u8 = 8;
+const SET_CONFIGURATION: u8 = 9;
+const GET_INTERFACE: u8 = 10;
+const SET_INTERFACE: u8 = 11;
+
+// Feature selectors
+const ENDPOINT_HALT: u16 = 0;
+const DEVICE_REMOTE_WAKEUP: u16 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EndpointType {
+    Control = 0,
+    Isochronous = 1,
+    Bulk = 2,
+    Interrupt = 3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Host to device
+    Out = 0,
+    /// Device to host
+    In = 1,
+}
+
+/// Describes one of the endpoints of the configuration.  Endpoint 1
+/// can have a FIFO of up to 256 bytes; the others are limited to 64.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EndpointConfig {
+    pub number: u8,
+    pub kind: EndpointType,
+    pub direction: Direction,
+    /// The FIFO size in bytes: 8, 16, 32, 64, 128 or 256
+    pub size: u16,
+    /// Use two banks so that one can be filled while the
+    /// other is being transferred
+    pub double_bank: bool,
+}
+
+impl EndpointConfig {
+    fn size_bits(&self) -> u8 {
+        match self.size {
+            0...8 => 0,
+            9...16 => 1,
+            17...32 => 2,
+            33...64 => 3,
+            65...128 => 4,
+            _ => 5,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Not yet reset by the host
+    Attached,
+    /// Reset, and responding on the default address
+    Default,
+    Addressed,
+    Configured,
+    Suspended,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The host has not selected a configuration
+    NotConfigured,
+    /// The data does not fit in the endpoint's FIFO
+    TooLong,
+}
+
+/// The 8 byte SETUP packet that starts a control transfer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Bits 6:5 of the request type; 0 for standard requests,
+    /// 1 for class requests and 2 for vendor requests
+    pub fn kind(&self) -> u8 {
+        (self.request_type >> 5) & 0b11
+    }
+
+    /// Bits 4:0 of the request type; 0 for the device, 1 for an
+    /// interface and 2 for an endpoint
+    pub fn recipient(&self) -> u8 {
+        self.request_type & 0x1f
+    }
+}
+
+/// A kind of USB device.  The methods are called from the USB
+/// interrupt handler.
+pub trait Class {
+    /// The endpoints to configure when the host selects configuration 1,
+    /// in ascending order of endpoint number
+    const ENDPOINTS: &'static [EndpointConfig];
+
+    /// Returns the descriptor requested by GET_DESCRIPTOR.  `kind` and
+    /// `index` are the high and low bytes of wValue, and `language` is
+    /// wIndex: the language ID for strings, or the interface number for
+    /// class descriptors.
+    fn descriptor(kind: u8, index: u8, language: u16) -> Option<ProgSlice>;
+
+    /// Handle a request that is not a standard request.  Return false
+    /// to stall the request.
+    fn control(_setup: &SetupPacket, _control: &mut Control) -> bool {
+        false
+    }
+
+    /// Called when the device is reset or the configuration changes
+    fn set_configured(_configured: bool) {}
+}
+
+struct Hooks {
+    endpoints: &'static [EndpointConfig],
+    descriptor: fn(u8, u8, u16) -> Option<ProgSlice>,
+    control: fn(&SetupPacket, &mut Control) -> bool,
+    set_configured: fn(bool),
+}
+
+static mut HOOKS: Option<Hooks> = None;
+static mut STATE: State = State::Attached;
+/// The state to return to on resume
+static mut SUSPENDED_FROM: State = State::Attached;
+static mut CONFIGURATION: u8 = 0;
+static mut REMOTE_WAKEUP_ENABLED: bool = false;
+/// Bitmask of the endpoints that have been handed out
+static mut TAKEN: u8 = 0;
+
+#[inline]
+fn usb() -> &'static UsbDevice {
+    unsafe { &(*USB_DEVICE.get()) }
+}
+
+#[inline]
+fn set_state(state: State) {
+    unsafe { write_volatile(&mut STATE, state) }
+}
+
+/// Returns the current state of the device
+pub fn state() -> State {
+    unsafe { read_volatile(&STATE) }
+}
+
+/// The frame number is 11 bits wide
+const FRAME_MASK: u16 = 0x7ff;
+
+/// Returns the frame number from the most recent start of frame
+/// packet.  The host sends one every millisecond while the bus is
+/// active, and the number wraps around after 2047.
+pub fn frame_count() -> u16 {
+    usb().udfnum.read() & FRAME_MASK
+}
+
+/// Returns the number of frames since frame_count() returned `earlier`,
+/// allowing for the frame number having wrapped around in between.
+pub fn frames_since(earlier: u16) -> u16 {
+    frame_count().wrapping_sub(earlier) & FRAME_MASK
+}
+
+#[inline]
+fn select_endpoint(number: u8) {
+    unsafe { usb().uenum.write(number) }
+}
+
+#[inline]
+fn ueintx() -> u8 {
+    usb().ueintx.read().bits()
+}
+
+/// The UEINTX flags are cleared by writing zero; writing one
+/// has no effect.
+#[inline]
+fn clear_ueintx(mask: u8) {
+    unsafe { usb().ueintx.write(UsbDeviceUeintxFlags::from_bits(!mask)) }
+}
+
+#[inline]
+fn set_ueienx(mask: u8) {
+    unsafe { usb().ueienx.write(UsbDeviceUeienxFlags::from_bits(mask)) }
+}
+
+/// Allocate the FIFO for the selected endpoint.  Returns false if
+/// the configuration was rejected.
+fn configure_endpoint(config: &EndpointConfig) -> bool {
+    select_endpoint(config.number);
+    unsafe {
+        let usb = usb();
+        usb.ueconx.write(EPEN);
+        usb.uecfg0x.write(UsbDeviceUecfg0xFlags::from_bits(
+            ((config.kind as u8) << 6) | config.direction as u8,
+        ));
+        let bank = if config.double_bank { 1 << 2 } else { 0 };
+        usb.uecfg1x.write(
+            UsbDeviceUecfg1xFlags::from_bits((config.size_bits() << 4) | bank) | ALLOC,
+        );
+        (usb.uesta0x.read() & CFGOK) == CFGOK
+    }
+}
+
+/// Disable the endpoint and release its FIFO
+fn deconfigure_endpoint(number: u8) {
+    select_endpoint(number);
+    unsafe {
+        let usb = usb();
+        usb.ueconx.write(UsbDeviceUeconxFlags::empty());
+        usb.uecfg1x.modify(|x| x - ALLOC);
+    }
+}
+
+fn configure_control_endpoint() {
+    configure_endpoint(&EndpointConfig {
+        number: 0,
+        kind: EndpointType::Control,
+        direction: Direction::Out,
+        size: ENDPOINT0_SIZE,
+        double_bank: false,
+    });
+    set_ueienx(RXSTP);
+}
+
+fn usb_general() {
+    let udint = usb().udint.read().bits();
+    let udien = usb().udien.read().bits();
+    // Acknowledge the events that we are about to handle
+    unsafe {
+        usb()
+            .udint
+            .write(UsbDeviceUdintFlags::from_bits(!(udint & udien)));
+    }
+
+    if udint & udien & EORST != 0 {
+        configure_control_endpoint();
+        unsafe {
+            write_volatile(&mut CONFIGURATION, 0);
+            write_volatile(&mut REMOTE_WAKEUP_ENABLED, false);
+            if let Some(ref hooks) = HOOKS {
+                (hooks.set_configured)(false);
+            }
+        }
+        set_state(State::Default);
+    }
+
+    if udint & udien & SUSP != 0 {
+        // Freeze the clock to save power, and wait to be woken
+        unsafe {
+            usb().usbcon.modify(|x| x | FRZCLK);
+            usb()
+                .udien
+                .write(UsbDeviceUdienFlags::from_bits((udien & !SUSP) | WAKEUP));
+            write_volatile(&mut SUSPENDED_FROM, state());
+        }
+        set_state(State::Suspended);
+    }
+
+    if udint & udien & WAKEUP != 0 {
+        unsafe {
+            usb().usbcon.modify(|x| x - FRZCLK);
+            // WAKEUPI is set again by the clock restarting
+            usb()
+                .udint
+                .write(UsbDeviceUdintFlags::from_bits(!WAKEUP));
+            usb()
+                .udien
+                .write(UsbDeviceUdienFlags::from_bits((udien & !WAKEUP) | SUSP));
+            set_state(read_volatile(&SUSPENDED_FROM));
+        }
+    }
+
+    sleep::set_event_pending();
+}
+
+irq_handler!(USB_GEN, usb_general);
+
+fn usb_endpoint() {
+    let saved = usb().uenum.read();
+
+    select_endpoint(0);
+    if ueintx() & RXSTP != 0 {
+        handle_setup();
+    }
+
+    // Wake the futures waiting on the other endpoints.  Their
+    // interrupts are re-enabled when they are next polled.
+    let pending = usb().ueint.read();
+    for number in 1..NUM_ENDPOINTS {
+        if pending & (1 << number) != 0 {
+            select_endpoint(number);
+            set_ueienx(0);
+        }
+    }
+
+    select_endpoint(saved);
+    sleep::set_event_pending();
+}
+
+irq_handler!(USB_COM, usb_endpoint);
+
+/// Read the SETUP packet from the control endpoint and acknowledge it
+fn read_setup() -> SetupPacket {
+    let usb = usb();
+    let mut bytes = [0u8; 8];
+    for byte in bytes.iter_mut() {
+        *byte = usb.uedatx.read();
+    }
+    // Also discard any OUT or IN state left over from the status stage
+    // of the previous transfer, so that the data stage of this one
+    // starts afresh
+    clear_ueintx(RXSTP | RXOUT | TXIN);
+    SetupPacket {
+        request_type: bytes[0],
+        request: bytes[1],
+        value: bytes[2] as u16 | (bytes[3] as u16) << 8,
+        index: bytes[4] as u16 | (bytes[5] as u16) << 8,
+        length: bytes[6] as u16 | (bytes[7] as u16) << 8,
+    }
+}
+
+fn handle_setup() {
+    let setup = read_setup();
+    let mut control = Control {
+        length: setup.length,
+    };
+
+    let handled = if setup.kind() == 0 {
+        handle_standard(&setup, &mut control)
+    } else {
+        unsafe {
+            match HOOKS {
+                Some(ref hooks) => (hooks.control)(&setup, &mut control),
+                None => false,
+            }
+        }
+    };
+
+    if !handled {
+        unsafe {
+            usb().ueconx.write(STALLRQ | EPEN);
+        }
+    }
+}
+
+fn handle_standard(setup: &SetupPacket, control: &mut Control) -> bool {
+    let hooks = match unsafe { HOOKS.as_ref() } {
+        Some(hooks) => hooks,
+        None => return false,
+    };
+
+    match setup.request {
+        GET_DESCRIPTOR => {
+            match (hooks.descriptor)((setup.value >> 8) as u8, setup.value as u8, setup.index) {
+                Some(descriptor) => {
+                    control.reply_prog(descriptor);
+                    true
+                }
+                None => false,
+            }
+        }
+        SET_ADDRESS => {
+            let address = UsbDeviceUdaddrFlags::from_bits(setup.value as u8 & 0x7f);
+            unsafe {
+                usb().udaddr.write(address);
+            }
+            // The new address takes effect after the status stage
+            control.accept();
+            wait_in_ready();
+            unsafe {
+                usb().udaddr.write(address | ADDEN);
+            }
+            set_state(if address.bits() != 0 {
+                State::Addressed
+            } else {
+                State::Default
+            });
+            true
+        }
+        GET_CONFIGURATION => {
+            control.reply(&[unsafe { read_volatile(&CONFIGURATION) }]);
+            true
+        }
+        SET_CONFIGURATION => {
+            let configuration = setup.value as u8;
+            if configuration > 1 {
+                return false;
+            }
+            for config in hooks.endpoints.iter() {
+                deconfigure_endpoint(config.number);
+            }
+            if configuration == 1 {
+                // Stall rather than report a configuration whose
+                // endpoints the controller rejected
+                let ok = hooks.endpoints.iter().all(configure_endpoint);
+                if !ok {
+                    for config in hooks.endpoints.iter() {
+                        deconfigure_endpoint(config.number);
+                    }
+                    select_endpoint(0);
+                    return false;
+                }
+                let mask = hooks
+                    .endpoints
+                    .iter()
+                    .fold(0u8, |mask, config| mask | 1 << config.number);
+                unsafe {
+                    usb().uerst.write(UsbDeviceUerstFlags::from_bits(mask));
+                    usb().uerst.write(UsbDeviceUerstFlags::empty());
+                }
+            }
+            select_endpoint(0);
+            control.accept();
+            unsafe {
+                write_volatile(&mut CONFIGURATION, configuration);
+            }
+            set_state(if configuration == 1 {
+                State::Configured
+            } else {
+                State::Addressed
+            });
+            (hooks.set_configured)(configuration == 1);
+            true
+        }
+        GET_STATUS => {
+            let status = match setup.recipient() {
+                0 => {
+                    if unsafe { read_volatile(&REMOTE_WAKEUP_ENABLED) } {
+                        2
+                    } else {
+                        0
+                    }
+                }
+                2 => {
+                    let number = setup.index as u8 & 0x7f;
+                    if number >= NUM_ENDPOINTS {
+                        return false;
+                    }
+                    select_endpoint(number);
+                    let halted = (usb().ueconx.read() & STALLRQ) == STALLRQ;
+                    select_endpoint(0);
+                    if halted {
+                        1
+                    } else {
+                        0
+                    }
+                }
+                _ => 0,
+            };
+            control.reply(&[status, 0]);
+            true
+        }
+        CLEAR_FEATURE | SET_FEATURE => {
+            let set = setup.request == SET_FEATURE;
+            match (setup.recipient(), setup.value) {
+                (0, DEVICE_REMOTE_WAKEUP) => unsafe {
+                    write_volatile(&mut REMOTE_WAKEUP_ENABLED, set);
+                },
+                (2, ENDPOINT_HALT) => {
+                    let number = setup.index as u8 & 0x7f;
+                    if number == 0 || number >= NUM_ENDPOINTS {
+                        return false;
+                    }
+                    select_endpoint(number);
+                    unsafe {
+                        if set {
+                            usb().ueconx.write(STALLRQ | EPEN);
+                        } else {
+                            usb().ueconx.write(STALLRQC | RSTDT | EPEN);
+                            usb().uerst.write(UsbDeviceUerstFlags::from_bits(1 << number));
+                            usb().uerst.write(UsbDeviceUerstFlags::empty());
+                        }
+                    }
+                    select_endpoint(0);
+                }
+                _ => return false,
+            }
+            control.accept();
+            true
+        }
+        GET_INTERFACE => {
+            control.reply(&[0]);
+            true
+        }
+        SET_INTERFACE => {
+            // Only the default alternate setting is supported
+            if setup.value != 0 {
+                return false;
+            }
+            control.accept();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Wait until the control endpoint can accept IN data
+fn wait_in_ready() {
+    while ueintx() & TXIN == 0 {}
+}
+
+/// The data and status stages of a control transfer.  Passed to
+/// `Class::control` with the control endpoint selected.
+pub struct Control {
+    /// wLength from the SETUP packet
+    length: u16,
+}
+
+impl Control {
+    /// Complete a request that has no data stage by sending a
+    /// zero length status packet
+    pub fn accept(&mut self) {
+        clear_ueintx(TXIN);
+    }
+
+    /// Send `data` as the data stage of a control read.  The data
+    /// is truncated to the length requested by the host.
+    pub fn reply(&mut self, data: &[u8]) {
+        self.send(data.len(), data.iter().cloned());
+    }
+
+    /// As `reply`, but for data stored in flash
+    pub fn reply_prog(&mut self, data: ProgSlice) {
+        self.send(data.len(), data.bytes());
+    }
+
+    fn send<I: Iterator<Item = u8>>(&mut self, len: usize, mut bytes: I) {
+        let usb = usb();
+        let mut remaining = if len < self.length as usize {
+            len
+        } else {
+            self.length as usize
+        };
+        loop {
+            // Wait for the bank to be free, or for the host to
+            // abort the data stage by starting the status stage
+            let status = loop {
+                let status = ueintx();
+                if status & (TXIN | RXOUT) != 0 {
+                    break status;
+                }
+            };
+            if status & RXOUT != 0 {
+                clear_ueintx(RXOUT);
+                return;
+            }
+
+            let chunk = if remaining < ENDPOINT0_SIZE as usize {
+                remaining
+            } else {
+                ENDPOINT0_SIZE as usize
+            };
+            for _ in 0..chunk {
+                unsafe {
+                    usb.uedatx.write(bytes.next().unwrap_or(0));
+                }
+            }
+            remaining -= chunk;
+            clear_ueintx(TXIN);
+
+            // A full packet needs to be followed by a short one (possibly
+            // empty) to tell the host that the data has ended.
+            if remaining == 0 && chunk != ENDPOINT0_SIZE as usize {
+                return;
+            }
+        }
+    }
+
+    /// Receive the data stage of a control write into `buf` and then
+    /// send the status packet.  Returns the number of bytes received.
+    pub fn receive(&mut self, buf: &mut [u8]) -> usize {
+        let usb = usb();
+        let mut received = 0;
+        while received < self.length as usize {
+            while ueintx() & RXOUT == 0 {}
+            let count = usb.uebclx.read() as usize;
+            for _ in 0..count {
+                let byte = usb.uedatx.read();
+                if received < buf.len() {
+                    buf[received] = byte;
+                }
+                received += 1;
+            }
+            clear_ueintx(RXOUT);
+            if count < ENDPOINT0_SIZE as usize {
+                break;
+            }
+        }
+        self.accept();
+        if received < buf.len() {
+            received
+        } else {
+            buf.len()
+        }
+    }
+}
+
+pub struct Usb {
+    _power: PowerHandle,
+    _pll: PllHandle,
+}
+
+impl Usb {
+    /// Power up the USB controller and attach to the bus as a `C`.
+    /// Returns None if there is already a live `Usb`.
+    pub fn new<C: Class>() -> Option<Self> {
+        {
+            let _cs = CriticalSection::new();
+            unsafe {
+                if HOOKS.is_some() {
+                    return None;
+                }
+                HOOKS = Some(Hooks {
+                    endpoints: C::ENDPOINTS,
+                    descriptor: C::descriptor,
+                    control: C::control,
+                    set_configured: C::set_configured,
+                });
+                write_volatile(&mut CONFIGURATION, 0);
+                write_volatile(&mut TAKEN, 0);
+            }
+        }
+        let power = power::acquire(power::Peripheral::Usb);
+        unsafe {
+            let usb = usb();
+            usb.uhwcon.write(UVREGE);
+            usb.usbcon.write(USBE | FRZCLK);
+        }
+        // The controller needs the 48MHz clock before it is unfrozen
+        let pll = pll::acquire();
+        unsafe {
+            let usb = usb();
+            usb.usbcon.write(USBE | OTGPADE);
+            usb.udcon.write(UsbDeviceUdconFlags::empty());
+            usb.udien
+                .write(UsbDeviceUdienFlags::from_bits(EORST | SUSP));
+        }
+        set_state(State::Attached);
+        Some(Self {
+            _power: power,
+            _pll: pll,
+        })
+    }
+
+    /// Returns true if the host has selected the configuration
+    pub fn is_configured(&self) -> bool {
+        state() == State::Configured
+    }
+
+    /// Ask a suspended host to resume, if it has allowed the device
+    /// to do so.  Returns true if the request was signalled.
+    pub fn remote_wakeup(&mut self) -> bool {
+        let _cs = CriticalSection::new();
+        if state() != State::Suspended || !unsafe { read_volatile(&REMOTE_WAKEUP_ENABLED) } {
+            return false;
+        }
+        unsafe {
+            usb().usbcon.modify(|x| x - FRZCLK);
+            usb().udcon.modify(|x| x | RMWKUP);
+        }
+        true
+    }
+
+    fn take_endpoint(
+        &mut self,
+        number: u8,
+        direction: Direction,
+    ) -> Option<&'static EndpointConfig> {
+        let hooks = unsafe { HOOKS.as_ref()? };
+        let config = hooks
+            .endpoints
+            .iter()
+            .find(|config| config.number == number && config.direction == direction)?;
+        let _cs = CriticalSection::new();
+        unsafe {
+            let taken = read_volatile(&TAKEN);
+            if taken & (1 << number) != 0 {
+                return None;
+            }
+            write_volatile(&mut TAKEN, taken | 1 << number);
+        }
+        Some(config)
+    }
+
+    /// Returns the IN endpoint `number` from the configuration, if
+    /// it has not already been taken
+    pub fn in_endpoint(&mut self, number: u8) -> Option<InEndpoint> {
+        let config = self.take_endpoint(number, Direction::In)?;
+        Some(InEndpoint {
+            number: config.number,
+            size: config.size,
+        })
+    }
+
+    /// Returns the OUT endpoint `number` from the configuration, if
+    /// it has not already been taken
+    pub fn out_endpoint(&mut self, number: u8) -> Option<OutEndpoint> {
+        let config = self.take_endpoint(number, Direction::Out)?;
+        Some(OutEndpoint {
+            number: config.number,
+        })
+    }
+}
+
+impl Drop for Usb {
+    fn drop(&mut self) {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let usb = usb();
+            usb.udien.write(UsbDeviceUdienFlags::empty());
+            usb.udcon.write(DETACH);
+            usb.usbcon.write(FRZCLK);
+            usb.uhwcon.write(UsbDeviceUhwconFlags::empty());
+            HOOKS = None;
+        }
+        set_state(State::Attached);
+    }
+}
+
+fn release_endpoint(number: u8) {
+    let _cs = CriticalSection::new();
+    unsafe {
+        write_volatile(&mut TAKEN, read_volatile(&TAKEN) & !(1 << number));
+    }
+}
+
+/// An endpoint that sends data to the host
+pub struct InEndpoint {
+    number: u8,
+    /// The size of the FIFO
+    size: u16,
+}
+
+impl InEndpoint {
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Queue `data` to be sent to the host in the next IN transaction.
+    /// Returns false if the endpoint's FIFO is still busy with an
+    /// earlier packet.  Data larger than the FIFO is rejected before
+    /// anything is written to the bank.
+    pub fn try_write(&mut self, data: &[u8]) -> Result<bool, Error> {
+        if data.len() > self.size as usize {
+            return Err(Error::TooLong);
+        }
+        let _cs = CriticalSection::new();
+        if state() != State::Configured {
+            return Err(Error::NotConfigured);
+        }
+        select_endpoint(self.number);
+        if ueintx() & RWAL == 0 {
+            return Ok(false);
+        }
+        let usb = usb();
+        for byte in data.iter() {
+            unsafe {
+                usb.uedatx.write(*byte);
+            }
+        }
+        // Hand the bank over to the controller
+        clear_ueintx(TXIN);
+        clear_ueintx(FIFOCON);
+        Ok(true)
+    }
+
+    /// As `try_write`, but arranges for the endpoint interrupt to wake
+    /// the event loop if the FIFO is busy.  For use by futures that
+    /// send on the endpoint.
+    pub fn poll_write(&mut self, data: &[u8]) -> Poll<(), Error> {
+        if self.try_write(data)? {
+            return Ok(Async::Ready(()));
+        }
+        // Wake when the bank frees up
+        let _cs = CriticalSection::new();
+        select_endpoint(self.number);
+        set_ueienx(TXIN);
+        Ok(Async::NotReady)
+    }
+
+    /// Returns a Future that sends `buf` to the host and then
+    /// resolves to the endpoint and the buffer
+    pub fn write<B: AsRef<[u8]>>(self, buf: B) -> Write<B> {
+        Write {
+            endpoint: Some(self),
+            buf: Some(buf),
+        }
+    }
+}
+
+impl Drop for InEndpoint {
+    fn drop(&mut self) {
+        release_endpoint(self.number);
+    }
+}
+
+pub struct Write<B> {
+    endpoint: Option<InEndpoint>,
+    buf: Option<B>,
+}
+
+impl<B: AsRef<[u8]>> Future for Write<B> {
+    type Item = (InEndpoint, B);
+    type Error = (InEndpoint, Error);
+
+    fn poll(&mut self) -> Poll<(InEndpoint, B), (InEndpoint, Error)> {
+        let result = {
+            let endpoint = self.endpoint.as_mut().expect("polled after completion");
+            let data = self.buf.as_ref().expect("polled after completion").as_ref();
+            endpoint.poll_write(data)
+        };
+        match result {
+            Ok(Async::Ready(())) => Ok(Async::Ready((
+                self.endpoint.take().unwrap(),
+                self.buf.take().unwrap(),
+            ))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(error) => Err((self.endpoint.take().unwrap(), error)),
+        }
+    }
+}
+
+/// An endpoint that receives data from the host
+pub struct OutEndpoint {
+    number: u8,
+}
+
+impl OutEndpoint {
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Copy a received packet into `buf`, returning its length, or
+    /// None if no packet is waiting.  Bytes that don't fit into `buf`
+    /// are discarded.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let _cs = CriticalSection::new();
+        if state() != State::Configured {
+            return Err(Error::NotConfigured);
+        }
+        select_endpoint(self.number);
+        if ueintx() & RXOUT == 0 {
+            return Ok(None);
+        }
+        clear_ueintx(RXOUT);
+        let usb = usb();
+        let count = usb.uebclx.read() as usize;
+        for i in 0..count {
+            let byte = usb.uedatx.read();
+            if i < buf.len() {
+                buf[i] = byte;
+            }
+        }
+        // Release the bank for the next packet
+        clear_ueintx(FIFOCON);
+        Ok(Some(if count < buf.len() { count } else { buf.len() }))
+    }
+
+    /// Returns a Future that receives the next packet into `buf`
+    /// and then resolves to the endpoint, the buffer and the length
+    /// of the packet
+    pub fn read<B: AsMut<[u8]>>(self, buf: B) -> Read<B> {
+        Read {
+            endpoint: Some(self),
+            buf: Some(buf),
+        }
+    }
+}
+
+impl Drop for OutEndpoint {
+    fn drop(&mut self) {
+        release_endpoint(self.number);
+    }
+}
+
+pub struct Read<B> {
+    endpoint: Option<OutEndpoint>,
+    buf: Option<B>,
+}
+
+impl<B: AsMut<[u8]>> Future for Read<B> {
+    type Item = (OutEndpoint, B, usize);
+    type Error = (OutEndpoint, Error);
+
+    fn poll(&mut self) -> Poll<(OutEndpoint, B, usize), (OutEndpoint, Error)> {
+        let result = {
+            let endpoint = self.endpoint.as_mut().expect("polled after completion");
+            let data = self.buf.as_mut().expect("polled after completion").as_mut();
+            endpoint.try_read(data)
+        };
+        match result {
+            Ok(Some(len)) => Ok(Async::Ready((
+                self.endpoint.take().unwrap(),
+                self.buf.take().unwrap(),
+                len,
+            ))),
+            Ok(None) => {
+                let _cs = CriticalSection::new();
+                select_endpoint(self.endpoint.as_ref().unwrap().number);
+                set_ueienx(RXOUT);
+                Ok(Async::NotReady)
+            }
+            Err(error) => Err((self.endpoint.take().unwrap(), error)),
+        }
+    }
+}
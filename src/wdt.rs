@@ -1,6 +1,9 @@
 use mcu;
 use mutex;
+use core::ptr::{read_volatile, write_volatile};
+use eventloop::TickSource;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Duration {
     Approx15ms = 15,
     Approx30ms = 30,
@@ -14,14 +17,43 @@ pub enum Duration {
     Approx8s = 8000,
 }
 
+impl Duration {
+    #[inline]
+    fn bits(&self) -> mcu::WdtWdtcsrFlags {
+        use self::Duration::*;
+        match *self {
+            Approx15ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_2K,
+            Approx30ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_4K,
+            Approx60ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_8K,
+            Approx120ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_16K,
+            Approx250ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_32K,
+            Approx500ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_64K,
+            Approx1s => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_128K,
+            Approx2s => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_256K,
+            Approx4s => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_512K,
+            Approx8s => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_1024K,
+        }
+    }
+}
+
+/// The function to call from the WDT interrupt vector
+static mut HANDLER: Option<fn()> = None;
+
+/// Set while a Ticker or a Supervisor owns the watchdog
+static mut CLAIMED: bool = false;
+
+/// The contents of MCUSR as they were at startup
+static mut RESET_FLAGS: mcu::CpuMcusrFlags = mcu::CpuMcusrFlags::empty();
+
 /// Called by the main startup code, so you won't generally need to call this.
 /// This function re-initializes the watchdog timer and disables it.
 pub fn initialize_disabled() {
     unsafe {
         let cpu = &(*mcu::CPU.get());
+        // Accumulate rather than overwrite; we may be called more than
+        // once during startup and WDRF is cleared below.
+        write_volatile(&mut RESET_FLAGS, read_volatile(&RESET_FLAGS) | cpu.mcusr.read());
         cpu.mcusr.modify(|x| x - mcu::CpuMcusrFlags::WDRF);
-        cpu.clkpr
-            .write(mcu::CpuClkprFlags::CPU_CLK_PRESCALE_4_BITS_SMALL_1);
 
         // Disable watchdog resets
         asm!("WDR"::::"volatile");
@@ -32,6 +64,12 @@ pub fn initialize_disabled() {
     }
 }
 
+/// Returns the reset flags from MCUSR as they were captured by
+/// initialize_disabled(), before the watchdog reset flag was cleared.
+pub fn reset_flags() -> mcu::CpuMcusrFlags {
+    unsafe { read_volatile(&RESET_FLAGS) }
+}
+
 /// Disable the watchdog timer
 pub fn disable() {
     mutex::interrupt_free(|_cs| unsafe {
@@ -40,38 +78,148 @@ pub fn disable() {
         wdt.wdtcsr
             .modify(|x| x | mcu::WdtWdtcsrFlags::WDCE | mcu::WdtWdtcsrFlags::WDE);
         wdt.wdtcsr.write(mcu::WdtWdtcsrFlags::empty());
+        write_volatile(&mut HANDLER, None);
     });
 }
 
 /// Reset the watchdog counter.  If the watchdog is enabled and the counter is
-/// not reset before the watchdog duration expires, an interrupt will be generated.
+/// not reset before the watchdog duration expires, the configured action
+/// (system reset and/or interrupt) will take place.
 pub fn reset() {
     unsafe {
         asm!("WDR"::::"volatile");
     }
 }
 
-/// Enable the watchdog and set the timer interval
-pub fn enable(duration: Duration) {
-    use self::Duration::*;
-    let mask = match duration {
-        Approx15ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_2K,
-        Approx30ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_4K,
-        Approx60ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_8K,
-        Approx120ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_16K,
-        Approx250ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_32K,
-        Approx500ms => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_64K,
-        Approx1s => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_128K,
-        Approx2s => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_256K,
-        Approx4s => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_512K,
-        Approx8s => mcu::WdtWdtcsrFlags::WDOG_TIMER_PRESCALE_4BITS_OSCILLATOR_CYCLES_1024K,
-    };
-
+/// Performs the timed WDCE sequence and sets the control register to
+/// the specified flags.
+fn configure(flags: mcu::WdtWdtcsrFlags, handler: Option<fn()>) {
     mutex::interrupt_free(|_cs| unsafe {
+        write_volatile(&mut HANDLER, handler);
         asm!("WDR"::::"volatile");
         let wdt = &(*mcu::WDT.get());
         wdt.wdtcsr
             .modify(|x| x | mcu::WdtWdtcsrFlags::WDCE | mcu::WdtWdtcsrFlags::WDE);
-        wdt.wdtcsr.write(mask);
+        wdt.wdtcsr.write(flags);
     });
 }
+
+/// Enable the watchdog in system reset mode and set the timer interval.
+/// If the counter is not reset() before the interval expires, the MCU
+/// will be reset.
+pub fn enable(duration: Duration) {
+    configure(duration.bits() | mcu::WdtWdtcsrFlags::WDE, None);
+}
+
+/// Enable the watchdog in interrupt mode.  `handler` is called from
+/// the WDT interrupt each time the interval expires; the MCU is not
+/// reset.  This is useful as a low power wakeup source because the
+/// watchdog oscillator keeps running in `SleepMode::PowerDown`.
+pub fn enable_interrupt(duration: Duration, handler: fn()) {
+    configure(duration.bits() | mcu::WdtWdtcsrFlags::WDIE, Some(handler));
+}
+
+/// Enable the watchdog in interrupt-then-reset mode.  When the interval
+/// first expires `handler` is called from the WDT interrupt and the
+/// hardware clears the interrupt enable bit; if the counter is still not
+/// reset() before the interval expires again, the MCU will be reset.
+/// `handler` is the last chance to save state before the reset.
+/// Calling this function again from `handler` re-arms the interrupt.
+pub fn enable_interrupt_then_reset(duration: Duration, handler: fn()) {
+    configure(
+        duration.bits() | mcu::WdtWdtcsrFlags::WDIE | mcu::WdtWdtcsrFlags::WDE,
+        Some(handler),
+    );
+}
+
+fn wdt_interrupt() {
+    unsafe {
+        if let Some(handler) = read_volatile(&HANDLER) {
+            handler();
+        }
+    }
+}
+
+irq_handler!(WDT, wdt_interrupt);
+
+/// Take ownership of the watchdog on behalf of a Ticker or Supervisor.
+/// Returns false if the other one already owns it.
+pub(crate) fn claim() -> bool {
+    let _cs = mutex::CriticalSection::new();
+    unsafe {
+        if read_volatile(&CLAIMED) {
+            return false;
+        }
+        write_volatile(&mut CLAIMED, true);
+    }
+    true
+}
+
+/// Give up ownership taken by claim()
+pub(crate) fn release() {
+    unsafe {
+        write_volatile(&mut CLAIMED, false);
+    }
+}
+
+/// The number of ticks to deliver per watchdog interrupt
+static mut TICKS_PER_INTERRUPT: u16 = 1;
+static mut TICK: Option<fn()> = None;
+
+fn ticker_interrupt() {
+    unsafe {
+        if let Some(tick) = read_volatile(&TICK) {
+            for _ in 0..read_volatile(&TICKS_PER_INTERRUPT) {
+                tick();
+            }
+        }
+    }
+}
+
+/// Drives the event loop from the watchdog interrupt.  The watchdog
+/// keeps running in `SleepMode::PowerDown`, so this allows the system
+/// to sleep much more deeply than the other timers, at the cost of
+/// precision: the watchdog oscillator is only accurate to around 10%.
+/// If the interval is longer than the tick period then several ticks
+/// are delivered per interrupt to keep the tick count approximately
+/// correct.
+/// This cannot be used together with a `Supervisor`, as both
+/// need to own the watchdog.
+pub struct Ticker {
+    duration: Duration,
+}
+
+impl Ticker {
+    /// Returns None if a Ticker or Supervisor already owns the watchdog
+    pub fn new(duration: Duration) -> Option<Self> {
+        if !claim() {
+            return None;
+        }
+        Some(Self { duration })
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        release();
+    }
+}
+
+impl TickSource for Ticker {
+    fn start(&mut self, hz: u16, tick: fn()) {
+        let tick_ms = 1000 / hz;
+        let ticks = (self.duration as u16 + tick_ms / 2) / tick_ms;
+        mutex::interrupt_free(|_cs| unsafe {
+            write_volatile(&mut TICKS_PER_INTERRUPT, if ticks == 0 { 1 } else { ticks });
+            write_volatile(&mut TICK, Some(tick));
+        });
+        enable_interrupt(self.duration, ticker_interrupt);
+    }
+
+    fn stop(&mut self) {
+        disable();
+        mutex::interrupt_free(|_cs| unsafe {
+            write_volatile(&mut TICK, None);
+        });
+    }
+}